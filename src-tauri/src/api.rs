@@ -1,13 +1,34 @@
-use crate::config::Config;
-use crate::scanner::{DetectedGame, Scanner};
+use crate::auth;
+use crate::chunkstore::{GcReport, VerifyReport};
+use crate::client::{ApiClient, ApiError, DeviceResponse, GameProfile, NewGameProfile};
+use crate::compression;
+use crate::config::{Config, StorageTarget};
+use crate::crypto::EncryptionKey;
+use crate::device::MachineInfo;
+use crate::diff::{self, VersionDiff};
+use crate::error::{AppError, AppResult};
+use crate::history::{
+    self, HistoryEntry, HistoryFilter, HistoryItem, UploadHistoryPage, UploadTrigger,
+};
+use crate::login::{self, DeviceLoginPrompt};
+use crate::profiles::ProfileResolver;
+use crate::progress::{ProgressReporter, ProgressStage};
+use crate::queue::{self, JobStatus, UploadJob, UploadQueue};
+use crate::restore::{self, ArchiveEntry, PlannedFile, RestoreReport};
+use crate::retention;
+use crate::safety::SafetySnapshot;
+use crate::scanner::{DetectedGame, Scanner, StoreIds};
+use crate::storage::{self, GameRef, LocalBackend, StorageBackend, StoredVersion};
+use crate::throttle;
 use crate::uploader::{UploadResult, Uploader};
+use crate::version_cache::{self, VersionHistoryPage};
 use crate::AppState;
-use keyring::Entry;
 use serde::{Deserialize, Serialize};
-use tauri::State;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tauri::{AppHandle, Manager, State};
 
-const KEYRING_SERVICE: &str = "saveknight-desktop";
-const KEYRING_USER: &str = "device-token";
+pub(crate) const KEYRING_SERVICE: &str = "saveknight-desktop";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthStatus {
@@ -23,186 +44,206 @@ pub struct LoginRequest {
     pub machine_id: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct DeviceResponse {
-    device_id: String,
-    token: String,
-    expires_at: String,
+#[tauri::command]
+pub async fn get_config(state: State<'_, AppState>) -> AppResult<Config> {
+    let config = state.config.lock()?;
+    Ok(config.clone())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct MeResponse {
-    device: DeviceInfo,
-    user: UserInfo,
-    subscription: SubscriptionInfo,
-}
+#[tauri::command]
+pub async fn save_config(state: State<'_, AppState>, new_config: Config) -> AppResult<()> {
+    throttle::validate(&new_config).map_err(AppError::InvalidInput)?;
+    compression::validate(&new_config.compression).map_err(AppError::InvalidInput)?;
+    retention::validate(&new_config.local_retention).map_err(AppError::InvalidInput)?;
+    for (game_name, settings) in &new_config.game_settings {
+        if let Some(compression) = &settings.compression {
+            compression::validate(compression)
+                .map_err(|e| AppError::InvalidInput(format!("{}: {}", game_name, e)))?;
+        }
+        if let Some(retention) = &settings.retention {
+            retention::validate(retention)
+                .map_err(|e| AppError::InvalidInput(format!("{}: {}", game_name, e)))?;
+        }
+    }
+    for rule in &new_config.restore_redirects {
+        if rule.source.is_empty() || !std::path::Path::new(&rule.target).is_absolute() {
+            return Err(AppError::InvalidInput(
+                "Redirect rules need a source prefix and an absolute target".to_string(),
+            ));
+        }
+    }
+    for (index, target) in new_config.storage_targets.iter().enumerate() {
+        storage::validate_target(target).map_err(AppError::InvalidInput)?;
+        if new_config.storage_targets[..index].iter().any(|t| t.id() == target.id()) {
+            return Err(AppError::InvalidInput(format!(
+                "Storage target {} is configured twice",
+                target.id()
+            )));
+        }
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct DeviceInfo {
-    id: String,
-    name: String,
+    let mut config = state.config.lock()?;
+    save_config_file(&new_config)?;
+    *config = new_config;
+    state
+        .bandwidth_limiter
+        .update(&config)
+        .map_err(AppError::Internal)?;
+    Ok(())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct UserInfo {
-    id: String,
-    email: Option<String>,
-    first_name: Option<String>,
-    last_name: Option<String>,
+/// What this machine reports about itself when it signs in, e.g. to
+/// suggest a device name.
+#[tauri::command]
+pub async fn get_machine_info() -> AppResult<MachineInfo> {
+    Ok(MachineInfo::current())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct SubscriptionInfo {
-    plan_name: String,
-}
+/// Stores the token from a completed sign-in and returns the new status.
+async fn finish_login(
+    state: State<'_, AppState>,
+    device_response: DeviceResponse,
+) -> AppResult<AuthStatus> {
+    auth::store(&device_response).map_err(AppError::Internal)?;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct GameProfile {
-    pub id: String,
-    pub name: String,
-    pub platform: String,
+    let mut config = state.config.lock()?;
+    config.device_id = Some(device_response.device_id.clone());
+    save_config_file(&config)?;
+    drop(config);
+
+    get_auth_status(state).await
 }
 
+/// Signs in through the browser: opens the authorize page and waits for it
+/// to redirect back to a listener on 127.0.0.1.
 #[tauri::command]
-pub async fn get_config(state: State<'_, AppState>) -> Result<Config, String> {
-    let config = state.config.lock().map_err(|e| e.to_string())?;
-    Ok(config.clone())
+pub async fn login_with_browser(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    device_name: String,
+) -> AppResult<AuthStatus> {
+    let client = api_client(&state)?;
+    let pkce = login::Pkce::new();
+    let listener = login::LoopbackListener::bind().await?;
+    let authorize_url = login::authorize_url(&client, &listener.redirect_uri, &pkce)?;
+    tauri::api::shell::open(&app.shell_scope(), authorize_url, None)
+        .map_err(|e| AppError::Internal(format!("Failed to open the browser: {}", e)))?;
+
+    let code = listener.wait_for_code(&pkce.state).await?;
+    let device_response = login::exchange_code(
+        &client,
+        &code,
+        &pkce,
+        &listener.redirect_uri,
+        &MachineInfo::current().registration(&device_name),
+    )
+    .await?;
+
+    finish_login(state, device_response).await
 }
 
+/// Starts a device-code sign-in, for when the browser can't reach this
+/// machine.
 #[tauri::command]
-pub async fn save_config(state: State<'_, AppState>, new_config: Config) -> Result<(), String> {
-    let mut config = state.config.lock().map_err(|e| e.to_string())?;
-    *config = new_config.clone();
-    config.save().map_err(|e| e.to_string())?;
-    Ok(())
+pub async fn start_device_login(state: State<'_, AppState>) -> AppResult<DeviceLoginPrompt> {
+    login::start_device_login(&api_client(&state)?).await
 }
 
+/// Waits for the code from `start_device_login` to be approved.
 #[tauri::command]
-pub async fn login(
+pub async fn finish_device_login(
     state: State<'_, AppState>,
-    session_cookie: String,
     device_name: String,
-) -> Result<AuthStatus, String> {
-    let config = state.config.lock().map_err(|e| e.to_string())?;
-    let api_url = config.api_url.clone();
-    drop(config);
-
-    let machine_id = get_machine_id();
-
-    let client = reqwest::Client::new();
-    let response = client
-        .post(format!("{}/api/devices/register", api_url))
-        .header("Cookie", format!("connect.sid={}", session_cookie))
-        .json(&serde_json::json!({
-            "deviceName": device_name,
-            "machineId": machine_id,
-            "deviceType": "windows"
-        }))
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
-
-    if !response.status().is_success() {
-        let error = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("Registration failed: {}", error));
-    }
-
-    let device_response: DeviceResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
-
-    let entry = Entry::new(KEYRING_SERVICE, KEYRING_USER)
-        .map_err(|e| format!("Keyring error: {}", e))?;
-    entry
-        .set_password(&device_response.token)
-        .map_err(|e| format!("Failed to store token: {}", e))?;
-
-    let mut config = state.config.lock().map_err(|e| e.to_string())?;
-    config.device_id = Some(device_response.device_id.clone());
-    config.save().map_err(|e| e.to_string())?;
-    drop(config);
+) -> AppResult<AuthStatus> {
+    let client = api_client(&state)?;
+    let device = MachineInfo::current().registration(&device_name);
+    let device_response = login::finish_device_login(&client, &device).await?;
+    finish_login(state, device_response).await
+}
 
-    get_auth_status(state).await
+#[tauri::command]
+pub async fn cancel_device_login() -> AppResult<()> {
+    login::cancel_device_login();
+    Ok(())
 }
 
 #[tauri::command]
-pub async fn logout(state: State<'_, AppState>) -> Result<(), String> {
-    let entry = Entry::new(KEYRING_SERVICE, KEYRING_USER);
-    if let Ok(entry) = entry {
-        entry.delete_password().ok();
-    }
+pub async fn logout(state: State<'_, AppState>) -> AppResult<()> {
+    auth::clear();
 
-    let mut config = state.config.lock().map_err(|e| e.to_string())?;
+    let mut config = state.config.lock()?;
     config.device_id = None;
-    config.save().map_err(|e| e.to_string())?;
+    save_config_file(&config)?;
 
     Ok(())
 }
 
 #[tauri::command]
-pub async fn get_auth_status(state: State<'_, AppState>) -> Result<AuthStatus, String> {
-    let config = state.config.lock().map_err(|e| e.to_string())?;
-    let api_url = config.api_url.clone();
+pub async fn get_auth_status(state: State<'_, AppState>) -> AppResult<AuthStatus> {
+    let config = state.config.lock()?;
+    let client = ApiClient::new(&config);
     let device_id = config.device_id.clone();
     drop(config);
 
-    let token = match get_stored_token() {
-        Some(t) => t,
-        None => {
-            return Ok(AuthStatus {
+    match client.me().await {
+        Ok(me) => Ok(AuthStatus {
+            is_authenticated: true,
+            device_id,
+            user_email: me.user.email,
+            plan_name: Some(me.subscription.plan_name),
+        }),
+        Err(e) => {
+            if !matches!(e, ApiError::Auth(_)) {
+                log::warn!("Couldn't check the SaveKnight account: {}", e);
+            }
+            Ok(AuthStatus {
                 is_authenticated: false,
                 device_id: None,
                 user_email: None,
                 plan_name: None,
-            });
-        }
-    };
-
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!("{}/api/devices/me", api_url))
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
-        .await;
-
-    match response {
-        Ok(resp) if resp.status().is_success() => {
-            let me: MeResponse = resp.json().await.map_err(|e| e.to_string())?;
-            Ok(AuthStatus {
-                is_authenticated: true,
-                device_id,
-                user_email: me.user.email,
-                plan_name: Some(me.subscription.plan_name),
             })
         }
-        _ => Ok(AuthStatus {
-            is_authenticated: false,
-            device_id: None,
-            user_email: None,
-            plan_name: None,
-        }),
     }
 }
 
+/// A SaveKnight API client using the current settings.
+fn api_client(state: &AppState) -> AppResult<ApiClient> {
+    let config = state.config.lock()?;
+    Ok(ApiClient::new(&config))
+}
+
+fn save_config_file(config: &Config) -> AppResult<()> {
+    config.save().map_err(|e| AppError::Io {
+        path: Some(Config::config_path()),
+        message: e.to_string(),
+    })
+}
+
+fn save_upload_queue(queue: &UploadQueue) -> AppResult<()> {
+    queue.save().map_err(|e| AppError::Io {
+        path: Some(UploadQueue::queue_path()),
+        message: e.to_string(),
+    })
+}
+
 #[tauri::command]
-pub async fn scan_games(state: State<'_, AppState>) -> Result<Vec<DetectedGame>, String> {
+pub async fn scan_games(state: State<'_, AppState>) -> AppResult<Vec<DetectedGame>> {
     {
-        let mut is_scanning = state.is_scanning.lock().map_err(|e| e.to_string())?;
+        let mut is_scanning = state.is_scanning.lock()?;
         if *is_scanning {
-            return Err("Scan already in progress".to_string());
+            return Err(AppError::Busy("Scan already in progress".to_string()));
         }
         *is_scanning = true;
     }
 
     let result = async {
-        let scanner = Scanner::new().await.map_err(|e| e.to_string())?;
+        let scanner = Scanner::new().await?;
         Ok(scanner.scan_all_games())
     }
     .await;
 
     {
-        let mut is_scanning = state.is_scanning.lock().map_err(|e| e.to_string())?;
+        let mut is_scanning = state.is_scanning.lock()?;
         *is_scanning = false;
     }
 
@@ -210,34 +251,145 @@ pub async fn scan_games(state: State<'_, AppState>) -> Result<Vec<DetectedGame>,
 }
 
 #[tauri::command]
-pub async fn get_detected_games(_state: State<'_, AppState>) -> Result<Vec<DetectedGame>, String> {
-    let scanner = Scanner::new().await.map_err(|e| e.to_string())?;
+pub async fn get_detected_games(_state: State<'_, AppState>) -> AppResult<Vec<DetectedGame>> {
+    let scanner = Scanner::new().await?;
     Ok(scanner.scan_all_games())
 }
 
 #[tauri::command]
 pub async fn upload_saves(
+    app: AppHandle,
     state: State<'_, AppState>,
     games: Vec<DetectedGame>,
-    game_profile_id: String,
-) -> Result<Vec<UploadResult>, String> {
-    let config = state.config.lock().map_err(|e| e.to_string())?;
-    let api_url = config.api_url.clone();
+) -> AppResult<Vec<UploadResult>> {
+    let config = state.config.lock()?;
+    let targets: Vec<String> = config.storage_targets.iter().map(StorageTarget::id).collect();
+    let uses_saveknight = config.storage_targets.contains(&StorageTarget::SaveKnight);
+    let encryption_key_id = if config.encryption_enabled {
+        Some(
+            config
+                .encryption_key_id
+                .clone()
+                .ok_or_else(|| {
+                    AppError::InvalidInput(
+                        "Encryption is enabled but no key has been set up".to_string(),
+                    )
+                })?,
+        )
+    } else {
+        None
+    };
     drop(config);
 
-    let token = get_stored_token().ok_or("Not authenticated")?;
-    let uploader = Uploader::new(&api_url, &token);
+    if targets.is_empty() {
+        return Err(AppError::InvalidInput(
+            "No storage targets are configured".to_string(),
+        ));
+    }
+    if uses_saveknight && auth::load().is_none() {
+        return Err(AppError::NotAuthenticated("Not authenticated".to_string()));
+    }
+    let mut uploader = Uploader::default();
+    if let Some(key_id) = &encryption_key_id {
+        uploader = uploader.with_encryption_key(EncryptionKey::load(key_id)?);
+    }
+    let uploader = Arc::new(uploader);
 
     let mut results = Vec::new();
+    let mut job_ids = Vec::new();
     for game in games {
-        match uploader.upload_game(&game, &game_profile_id).await {
-            Ok(result) => results.push(result),
-            Err(e) => results.push(UploadResult {
-                game_name: game.name,
+        let job_id = UploadQueue::new_job_id();
+        let archive_path = UploadQueue::staging_dir().join(format!("{}.zip", job_id));
+
+        let progress = ProgressReporter::new(&app, &job_id, &game.name);
+        let (compression, game_profile_id) = {
+            let config = state.config.lock()?;
+            // Games without a profile yet get one when their job is uploaded.
+            (
+                config.compression_for(&game.name),
+                config.game_profiles.get(&game.name).cloned().unwrap_or_default(),
+            )
+        };
+
+        // Copying, compressing and encrypting the saves is blocking work.
+        let staged = {
+            let uploader = uploader.clone();
+            let game = game.clone();
+            let progress = progress.clone();
+            tokio::task::spawn_blocking(move || {
+                uploader.stage_game(&game, &archive_path, &compression, &progress)
+            })
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+        };
+        match staged {
+            Ok(staged) => {
+                // One job per target, all sharing the staged archive, so each
+                // target retries on its own schedule.
+                let now = chrono::Utc::now();
+                let mut upload_queue = state.upload_queue.lock()?;
+                for (index, target) in targets.iter().enumerate() {
+                    let id = if index == 0 { job_id.clone() } else { UploadQueue::new_job_id() };
+                    upload_queue.enqueue(UploadJob {
+                        id: id.clone(),
+                        game_name: game.name.clone(),
+                        game_profile_id: game_profile_id.clone(),
+                        archive_path: staged.path.to_string_lossy().to_string(),
+                        local_path: game.paths.first().map(|p| p.resolved_path.clone()).unwrap_or_default(),
+                        checksum: staged.checksum.clone(),
+                        size_bytes: staged.size_bytes,
+                        status: JobStatus::Pending,
+                        attempts: 0,
+                        last_error: None,
+                        created_at: now,
+                        next_attempt_at: now,
+                        key_id: encryption_key_id.clone(),
+                        mapping: Some(staged.mapping.clone()),
+                        target: target.clone(),
+                        store_ids: game.store_ids.clone(),
+                    });
+                    job_ids.push((id, game.name.clone(), target.clone()));
+                }
+                save_upload_queue(&upload_queue)?;
+            }
+            Err(e) => {
+                let e = AppError::from(e);
+                progress.finished(ProgressStage::Failed, &e.to_string());
+                history::record(&HistoryEntry::staging_failed(
+                    &game,
+                    &game_profile_id,
+                    UploadTrigger::Manual,
+                    &e,
+                ));
+                results.push(UploadResult {
+                    game_name: game.name,
+                    success: false,
+                    message: e.to_string(),
+                    code: Some(e.code().to_string()),
+                    upload_id: None,
+                    version_number: None,
+                    job_id: None,
+                    target: None,
+                    queued: false,
+                });
+            }
+        }
+    }
+
+    let mut drained = queue::drain(&app, UploadTrigger::Manual).await;
+    for (job_id, game_name, target) in job_ids {
+        match drained.iter().position(|r| r.job_id.as_deref() == Some(job_id.as_str())) {
+            Some(index) => results.push(drained.remove(index)),
+            None => results.push(UploadResult {
+                game_name,
                 success: false,
-                message: e.to_string(),
+                message: "Queued for upload".to_string(),
+                code: None,
                 upload_id: None,
                 version_number: None,
+                job_id: Some(job_id),
+                target: Some(target),
+                queued: true,
             }),
         }
     }
@@ -246,113 +398,705 @@ pub async fn upload_saves(
 }
 
 #[tauri::command]
-pub async fn get_upload_history(_state: State<'_, AppState>) -> Result<Vec<serde_json::Value>, String> {
-    Ok(Vec::new())
+pub async fn get_upload_queue(state: State<'_, AppState>) -> AppResult<Vec<UploadJob>> {
+    let queue = state.upload_queue.lock()?;
+    Ok(queue.jobs().to_vec())
 }
 
 #[tauri::command]
-pub async fn get_game_profiles(state: State<'_, AppState>) -> Result<Vec<GameProfile>, String> {
-    let config = state.config.lock().map_err(|e| e.to_string())?;
-    let api_url = config.api_url.clone();
-    drop(config);
+pub async fn retry_upload_job(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    job_id: Option<String>,
+) -> AppResult<Vec<UploadResult>> {
+    {
+        let mut upload_queue = state.upload_queue.lock()?;
+        if upload_queue.retry(job_id.as_deref()) == 0 {
+            if let Some(id) = job_id {
+                return Err(AppError::NotFound(format!("No queued upload with id {}", id)));
+            }
+        }
+        save_upload_queue(&upload_queue)?;
+    }
+
+    Ok(queue::drain(&app, UploadTrigger::Retry).await)
+}
+
+#[tauri::command]
+pub async fn remove_upload_job(state: State<'_, AppState>, job_id: String) -> AppResult<()> {
+    let mut upload_queue = state.upload_queue.lock()?;
+    match upload_queue.jobs().iter().find(|j| j.id == job_id) {
+        None => {
+            return Err(AppError::NotFound(format!(
+                "No queued upload with id {}",
+                job_id
+            )))
+        }
+        Some(job) if job.status == JobStatus::Uploading => {
+            return Err(AppError::Busy(
+                "Upload is in progress and can't be removed".to_string(),
+            ));
+        }
+        Some(_) => {}
+    }
+    upload_queue.remove(&job_id);
+    save_upload_queue(&upload_queue)?;
+    Ok(())
+}
+
+async fn storage_backend(
+    state: &AppState,
+    target: &str,
+) -> AppResult<Arc<dyn StorageBackend>> {
+    let config = state.config.lock()?.clone();
+    let token = match target {
+        "saveknight" => auth::valid_token(&ApiClient::new(&config)).await,
+        _ => None,
+    };
+    let backends = storage::backends_from_config(
+        &config,
+        token.as_deref(),
+        Some(state.bandwidth_limiter.clone()),
+    );
+
+    backends
+        .into_iter()
+        .find(|b| b.id() == target)
+        .ok_or_else(|| match target {
+            "saveknight" => AppError::NotAuthenticated("Not authenticated".to_string()),
+            _ => AppError::NotFound(format!(
+                "Storage target {} is not configured or has no credentials",
+                target
+            )),
+        })
+}
+
+/// One page of a game's version history, newest first. When the target
+/// can't be reached the last fetched copy of the page is returned instead,
+/// so the history can still be browsed offline.
+#[tauri::command]
+pub async fn get_version_history(
+    state: State<'_, AppState>,
+    game: GameRef,
+    target: Option<String>,
+    page: Option<u32>,
+    page_size: Option<u32>,
+) -> AppResult<VersionHistoryPage> {
+    let config = state.config.lock()?.clone();
+    let target = match target {
+        Some(target) => target,
+        None => default_target(&config)?,
+    };
+    let page = page.unwrap_or(1).max(1);
+    let page_size = page_size.unwrap_or(20).clamp(1, 100);
 
-    let token = get_stored_token().ok_or("Not authenticated")?;
+    // A target that isn't configured or signed in to isn't offline, so its
+    // error is returned rather than a cached page.
+    let backend = storage_backend(&state, &target).await?;
+    match backend.list_versions_page(&game, page, page_size).await {
+        Ok(fetched) => {
+            let history = VersionHistoryPage {
+                page: fetched,
+                fetched_at: chrono::Utc::now(),
+                from_cache: false,
+            };
+            if let Err(e) = version_cache::store(&target, &game, &history) {
+                log::warn!("Failed to cache version history: {}", e);
+            }
+            Ok(history)
+        }
+        Err(e) if e.is_unreachable() => {
+            version_cache::load(&target, &game, page, page_size).ok_or_else(|| e.into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Restores a backup version to the game's save locations on this machine.
+/// Without a target, the first configured storage target is used. When the
+/// version was encrypted with a passphrase key this device doesn't have,
+/// the call fails with `key_required` until the passphrase is given.
+#[tauri::command]
+pub async fn restore_save(
+    state: State<'_, AppState>,
+    game: GameRef,
+    version_id: String,
+    target: Option<String>,
+    passphrase: Option<String>,
+) -> AppResult<RestoreReport> {
+    run_restore(&state, &game, &version_id, target, None, passphrase.as_deref()).await
+}
+
+/// Restores only the given entries of a backup version, e.g. a single save
+/// slot. Names are as returned by `list_version_entries`.
+#[tauri::command]
+pub async fn restore_save_files(
+    state: State<'_, AppState>,
+    game: GameRef,
+    version_id: String,
+    files: Vec<String>,
+    target: Option<String>,
+    passphrase: Option<String>,
+) -> AppResult<RestoreReport> {
+    if files.is_empty() {
+        return Err(AppError::InvalidInput(
+            "No files selected to restore".to_string(),
+        ));
+    }
+    run_restore(
+        &state,
+        &game,
+        &version_id,
+        target,
+        Some(&files),
+        passphrase.as_deref(),
+    )
+    .await
+}
+
+async fn run_restore(
+    state: &State<'_, AppState>,
+    game: &GameRef,
+    version_id: &str,
+    target: Option<String>,
+    only: Option<&[String]>,
+    passphrase: Option<&str>,
+) -> AppResult<RestoreReport> {
+    let config = state.config.lock()?.clone();
+    let target = match target {
+        Some(target) => target,
+        None => default_target(&config)?,
+    };
+    let backend = storage_backend(state, &target).await?;
+
+    let report =
+        restore::restore_version(backend.as_ref(), game, version_id, only, passphrase, &config)
+        .await
+        .map_err(|e| AppError::from(e).for_game(&game.name))?;
+    log::info!(
+        "Restored {} of {} files for {} from version {}",
+        report.restored,
+        report.files.len(),
+        report.game_name,
+        report.version_id
+    );
+    Ok(report)
+}
+
+/// Lists the files in a backup version, with where each would be restored.
+#[tauri::command]
+pub async fn list_version_entries(
+    state: State<'_, AppState>,
+    game: GameRef,
+    version_id: String,
+    target: Option<String>,
+) -> AppResult<Vec<ArchiveEntry>> {
+    let config = state.config.lock()?.clone();
+    let target = match target {
+        Some(target) => target,
+        None => default_target(&config)?,
+    };
+    let backend = storage_backend(&state, &target).await?;
 
-    let client = reqwest::Client::new();
-    let response = client
-        .get(format!("{}/api/devices/game-profiles", api_url))
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
+    restore::list_entries(backend.as_ref(), &game, &version_id, &config)
         .await
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| AppError::from(e).for_game(&game.name))
+}
 
-    if response.status().is_success() {
-        let profiles: Vec<GameProfile> = response.json().await.map_err(|e| e.to_string())?;
-        Ok(profiles)
-    } else {
-        Err("Failed to fetch game profiles".to_string())
+/// Shows where each file of a version would be restored to, without
+/// downloading or writing anything.
+#[tauri::command]
+pub async fn preview_restore(
+    state: State<'_, AppState>,
+    game: GameRef,
+    version_id: String,
+    target: Option<String>,
+) -> AppResult<Vec<PlannedFile>> {
+    let config = state.config.lock()?.clone();
+    let target = match target {
+        Some(target) => target,
+        None => default_target(&config)?,
+    };
+    let backend = storage_backend(&state, &target).await?;
+
+    let version = backend
+        .list_versions(&game)
+        .await
+        .map_err(|e| AppError::from(e).for_game(&game.name))?
+        .into_iter()
+        .find(|v| v.id == version_id)
+        .ok_or_else(|| AppError::NotFound(format!("Version {} not found", version_id)))?;
+    let mapping = version.mapping.ok_or_else(|| {
+        AppError::InvalidInput(
+            "This version has no file mapping; it can only be previewed after downloading"
+                .to_string(),
+        )
+    })?;
+
+    Ok(restore::plan_restore(&mapping, &config.restore_redirects))
+}
+
+/// Compares a game's current saves with a backup version: which files were
+/// added, removed or modified since, with size and modification time
+/// differences. Uses the hashes stored with the version where available.
+#[tauri::command]
+pub async fn diff_save_version(
+    state: State<'_, AppState>,
+    game: GameRef,
+    version_id: String,
+    target: Option<String>,
+) -> AppResult<VersionDiff> {
+    let config = state.config.lock()?.clone();
+    let target = match target {
+        Some(target) => target,
+        None => default_target(&config)?,
+    };
+    let backend = storage_backend(&state, &target).await?;
+
+    let mapping = restore::version_mapping(backend.as_ref(), &game, &version_id)
+        .await
+        .map_err(|e| AppError::from(e).for_game(&game.name))?;
+    let scanner = Scanner::new().await?;
+    let local_files = scanner
+        .scan_game(&mapping.game_name)
+        .map(|detected| Scanner::inventory(&detected))
+        .unwrap_or_default();
+
+    Ok(diff::diff_version(
+        &mapping,
+        &version_id,
+        &local_files,
+        &config.restore_redirects,
+    ))
+}
+
+/// Applies the retention policy to a game's versions on a local target right
+/// away, e.g. after the policy was tightened. Returns the deleted version IDs.
+#[tauri::command]
+pub async fn prune_local_versions(
+    state: State<'_, AppState>,
+    target: String,
+    game_name: String,
+) -> AppResult<Vec<String>> {
+    let config = state.config.lock()?.clone();
+    let is_local = config
+        .storage_targets
+        .iter()
+        .any(|t| t.id() == target && matches!(t, StorageTarget::Local { .. }));
+    if !is_local {
+        return Err(AppError::InvalidInput(format!(
+            "{} is not a local storage target",
+            target
+        )));
     }
+    let backend = storage_backend(&state, &target).await?;
+
+    let game = GameRef {
+        name: game_name,
+        profile_id: None,
+    };
+    retention::apply(backend.as_ref(), &game, &config.retention_for(&game.name))
+        .await
+        .map_err(|e| AppError::from(e).for_game(&game.name))
 }
 
+/// Reads back every chunk of a dedup local target and reports corrupt
+/// chunks and the versions they affect.
 #[tauri::command]
-pub async fn create_game_profile(
+pub async fn verify_local_store(
     state: State<'_, AppState>,
-    name: String,
-    platform: String,
-) -> Result<GameProfile, String> {
-    let config = state.config.lock().map_err(|e| e.to_string())?;
-    let api_url = config.api_url.clone();
+    target: String,
+) -> AppResult<VerifyReport> {
+    let backend = dedup_backend(&state, &target)?;
+    Ok(tokio::task::spawn_blocking(move || backend.verify_store())
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))??)
+}
+
+/// Frees the space of chunks no version of a dedup local target uses.
+#[tauri::command]
+pub async fn gc_local_store(
+    state: State<'_, AppState>,
+    target: String,
+) -> AppResult<GcReport> {
+    let backend = dedup_backend(&state, &target)?;
+    Ok(tokio::task::spawn_blocking(move || backend.gc_store())
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))??)
+}
+
+fn dedup_backend(state: &AppState, target: &str) -> AppResult<LocalBackend> {
+    let config = state.config.lock()?;
+    config
+        .storage_targets
+        .iter()
+        .find_map(|t| match t {
+            StorageTarget::Local { name, path, dedup: true } if t.id() == target => {
+                Some(LocalBackend::new(name, std::path::Path::new(path), true))
+            }
+            _ => None,
+        })
+        .ok_or_else(|| {
+            AppError::InvalidInput(format!(
+                "{} is not a local target with dedup enabled",
+                target
+            ))
+        })
+}
+
+fn default_target(config: &Config) -> AppResult<String> {
+    config
+        .storage_targets
+        .first()
+        .map(StorageTarget::id)
+        .ok_or_else(|| AppError::InvalidInput("No storage targets are configured".to_string()))
+}
+
+#[tauri::command]
+pub async fn list_safety_snapshots(game_name: String) -> AppResult<Vec<SafetySnapshot>> {
+    Ok(SafetySnapshot::list(&game_name))
+}
+
+/// Rolls a game's saves back to a safety snapshot taken before a restore,
+/// by default the newest one. Only touches local files, so it works offline.
+#[tauri::command]
+pub async fn undo_restore(
+    game_name: String,
+    snapshot_id: Option<String>,
+) -> AppResult<RestoreReport> {
+    let snapshots = SafetySnapshot::list(&game_name);
+    let snapshot = match &snapshot_id {
+        Some(id) => snapshots.iter().find(|s| &s.id == id),
+        None => snapshots.first(),
+    }
+    .ok_or_else(|| AppError::NotFound(format!("No safety snapshot to undo for {}", game_name)))?;
+
+    let files = snapshot.undo();
+    let restored = files.iter().filter(|f| f.success).count() as u32;
+    let failed = files.len() as u32 - restored;
+    // Keep the snapshot if anything failed so the undo can be retried.
+    if failed == 0 {
+        snapshot.delete()?;
+    }
+
+    Ok(RestoreReport {
+        game_name,
+        version_id: snapshot.restored_version_id.clone(),
+        target: "safety-snapshot".to_string(),
+        files,
+        restored,
+        failed,
+        safety_snapshot_id: (failed > 0).then(|| snapshot.id.clone()),
+    })
+}
+
+/// Stores the secret of a storage target: the S3 secret access key or the
+/// WebDAV password.
+#[tauri::command]
+pub async fn set_storage_secret(
+    state: State<'_, AppState>,
+    target: String,
+    secret: String,
+) -> AppResult<()> {
+    let config = state.config.lock()?;
+    if !config.storage_targets.iter().any(|t| t.id() == target) {
+        return Err(AppError::NotFound(format!(
+            "Storage target {} is not configured",
+            target
+        )));
+    }
     drop(config);
 
-    let token = get_stored_token().ok_or("Not authenticated")?;
-
-    let client = reqwest::Client::new();
-    let response = client
-        .post(format!("{}/api/devices/game-profiles", api_url))
-        .header("Authorization", format!("Bearer {}", token))
-        .json(&serde_json::json!({
-            "name": name,
-            "platform": platform,
-        }))
-        .send()
+    storage::store_secret(&target, &secret).map_err(AppError::Internal)
+}
+
+#[tauri::command]
+pub async fn list_backup_versions(
+    state: State<'_, AppState>,
+    target: String,
+    game: GameRef,
+) -> AppResult<Vec<StoredVersion>> {
+    let backend = storage_backend(&state, &target).await?;
+    backend
+        .list_versions(&game)
         .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+        .map_err(|e| AppError::from(e).for_game(&game.name))
+}
 
-    if response.status().is_success() {
-        let profile: GameProfile = response.json().await.map_err(|e| e.to_string())?;
-        Ok(profile)
-    } else {
-        let error = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-        Err(format!("Failed to create game profile: {}", error))
+#[tauri::command]
+pub async fn delete_backup_version(
+    state: State<'_, AppState>,
+    target: String,
+    game: GameRef,
+    version_id: String,
+) -> AppResult<()> {
+    let backend = storage_backend(&state, &target).await?;
+    backend
+        .delete_version(&game, &version_id)
+        .await
+        .map_err(|e| AppError::from(e).for_game(&game.name))
+}
+
+/// Past backup attempts, newest first. Unless `reconcile` is false, the
+/// successful uploads on the page are checked against the versions now on
+/// their targets; targets that can't be reached are skipped.
+#[tauri::command]
+pub async fn get_upload_history(
+    state: State<'_, AppState>,
+    filter: Option<HistoryFilter>,
+    page: Option<u32>,
+    page_size: Option<u32>,
+    reconcile: Option<bool>,
+) -> AppResult<UploadHistoryPage> {
+    let page = page.unwrap_or(1).max(1);
+    let page_size = page_size.unwrap_or(50).clamp(1, 500);
+    let mut history = history::query(&filter.unwrap_or_default(), page, page_size);
+    if reconcile.unwrap_or(true) {
+        history.reconciled = reconcile_history(&state, &mut history.entries).await;
+    }
+    Ok(history)
+}
+
+/// Returns whether every target could be checked.
+async fn reconcile_history(state: &AppState, items: &mut [HistoryItem]) -> bool {
+    let mut complete = true;
+    let mut unreachable: Vec<String> = Vec::new();
+    for (target, game) in history::reconcile_groups(items) {
+        if unreachable.contains(&target) {
+            continue;
+        }
+        let versions = match storage_backend(state, &target).await {
+            Ok(backend) => backend.list_versions(&game).await.map_err(AppError::from),
+            Err(e) => Err(e),
+        };
+        match versions {
+            Ok(versions) => history::reconcile(items, &target, &game, &versions),
+            Err(e) => {
+                log::warn!(
+                    "Couldn't check the history of {} against {}: {}",
+                    game.name,
+                    target,
+                    e
+                );
+                complete = false;
+                if !matches!(e, AppError::NotFound(_) | AppError::Rejected { .. }) {
+                    unreachable.push(target);
+                }
+            }
+        }
     }
+    complete
 }
 
-fn get_stored_token() -> Option<String> {
-    let entry = Entry::new(KEYRING_SERVICE, KEYRING_USER).ok()?;
-    entry.get_password().ok()
+#[tauri::command]
+pub async fn get_game_profiles(state: State<'_, AppState>) -> AppResult<Vec<GameProfile>> {
+    Ok(api_client(&state)?.game_profiles().await?)
 }
 
-fn get_machine_id() -> String {
-    #[cfg(target_os = "windows")]
-    {
-        std::process::Command::new("wmic")
-            .args(["csproduct", "get", "uuid"])
-            .output()
-            .ok()
-            .and_then(|output| {
-                String::from_utf8(output.stdout)
-                    .ok()
-                    .and_then(|s| s.lines().nth(1).map(|l| l.trim().to_string()))
-            })
-            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+#[tauri::command]
+pub async fn create_game_profile(
+    state: State<'_, AppState>,
+    name: String,
+    platform: String,
+    store_ids: Option<StoreIds>,
+) -> AppResult<GameProfile> {
+    Ok(api_client(&state)?
+        .create_game_profile(&NewGameProfile {
+            name: &name,
+            platform: &platform,
+            store_ids: store_ids.unwrap_or_default(),
+        })
+        .await?)
+}
+
+/// The game profile each game backs up to, by game name.
+#[tauri::command]
+pub async fn get_game_profile_mappings(
+    state: State<'_, AppState>,
+) -> AppResult<HashMap<String, String>> {
+    Ok(state.config.lock()?.game_profiles.clone())
+}
+
+/// Sends a game's backups to another game profile, including queued ones
+/// that haven't started. `None` goes back to picking one automatically.
+#[tauri::command]
+pub async fn set_game_profile_mapping(
+    state: State<'_, AppState>,
+    game_name: String,
+    game_profile_id: Option<String>,
+) -> AppResult<()> {
+    if let Some(profile_id) = &game_profile_id {
+        let client = api_client(&state)?;
+        if !ProfileResolver::new(&client).exists(profile_id).await? {
+            return Err(AppError::NotFound(format!(
+                "Game profile {} not found",
+                profile_id
+            )));
+        }
     }
 
-    #[cfg(not(target_os = "windows"))]
     {
-        uuid::Uuid::new_v4().to_string()
+        let mut config = state.config.lock()?;
+        match &game_profile_id {
+            Some(profile_id) => config
+                .game_profiles
+                .insert(game_name.clone(), profile_id.clone()),
+            None => config.game_profiles.remove(&game_name),
+        };
+        save_config_file(&config)?;
     }
+
+    let mut upload_queue = state.upload_queue.lock()?;
+    upload_queue.set_game_profile(&game_name, game_profile_id.as_deref());
+    save_upload_queue(&upload_queue)?;
+    Ok(())
 }
 
-mod uuid {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    
-    pub struct Uuid;
-    
-    impl Uuid {
-        pub fn new_v4() -> UuidV4 {
-            let timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos();
-            UuidV4(format!("{:032x}", timestamp))
-        }
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionStatus {
+    pub enabled: bool,
+    pub active_key_id: Option<String>,
+    pub key_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionKeySetup {
+    pub key_id: String,
+    pub recovery_key: String,
+}
+
+#[tauri::command]
+pub async fn get_encryption_status(state: State<'_, AppState>) -> AppResult<EncryptionStatus> {
+    let config = state.config.lock()?;
+    Ok(EncryptionStatus {
+        enabled: config.encryption_enabled,
+        active_key_id: config.encryption_key_id.clone(),
+        key_ids: config.encryption_key_ids.clone(),
+    })
+}
+
+/// Turns on client-side encryption with the existing key, or a new one
+/// created from `passphrase`, or randomly when none is given. A passphrase
+/// can't replace an existing key here; that is what
+/// [`rotate_encryption_key`] is for.
+#[tauri::command]
+pub async fn enable_encryption(
+    state: State<'_, AppState>,
+    passphrase: Option<String>,
+) -> AppResult<EncryptionKeySetup> {
+    if passphrase.is_some() && state.config.lock()?.encryption_key_id.is_some() {
+        return Err(key_already_set_up());
     }
-    
-    pub struct UuidV4(String);
-    
-    impl UuidV4 {
-        pub fn to_string(&self) -> String {
-            self.0.clone()
-        }
+    // Derived before taking the lock, as Argon2 takes a while.
+    let derived = match passphrase {
+        Some(passphrase) => Some(derive_passphrase_key(passphrase).await?),
+        None => None,
+    };
+    let mut config = state.config.lock()?;
+
+    let key = match (&config.encryption_key_id, derived) {
+        (Some(key_id), None) => EncryptionKey::load(key_id)?,
+        (Some(_), Some(_)) => return Err(key_already_set_up()),
+        (None, Some(key)) => install_encryption_key(&mut config, key)?,
+        (None, None) => install_encryption_key(&mut config, EncryptionKey::generate())?,
+    };
+
+    config.encryption_enabled = true;
+    save_config_file(&config)?;
+
+    Ok(EncryptionKeySetup {
+        key_id: key.key_id(),
+        recovery_key: key.recovery_key(),
+    })
+}
+
+fn key_already_set_up() -> AppError {
+    AppError::InvalidInput(
+        "An encryption key is already set up; rotate the key to use a passphrase".to_string(),
+    )
+}
+
+#[tauri::command]
+pub async fn disable_encryption(state: State<'_, AppState>) -> AppResult<()> {
+    let mut config = state.config.lock()?;
+    config.encryption_enabled = false;
+    save_config_file(&config)?;
+    Ok(())
+}
+
+/// Replaces the active key. Older keys stay in the keyring so versions they
+/// encrypted can still be restored.
+#[tauri::command]
+pub async fn rotate_encryption_key(
+    state: State<'_, AppState>,
+    passphrase: Option<String>,
+) -> AppResult<EncryptionKeySetup> {
+    let key = match passphrase {
+        Some(passphrase) => derive_passphrase_key(passphrase).await?,
+        None => EncryptionKey::generate(),
+    };
+
+    let mut config = state.config.lock()?;
+    let key = install_encryption_key(&mut config, key)?;
+    save_config_file(&config)?;
+
+    Ok(EncryptionKeySetup {
+        key_id: key.key_id(),
+        recovery_key: key.recovery_key(),
+    })
+}
+
+#[tauri::command]
+pub async fn get_recovery_key(
+    state: State<'_, AppState>,
+    key_id: Option<String>,
+) -> AppResult<String> {
+    let config = state.config.lock()?;
+    let key_id = key_id
+        .or_else(|| config.encryption_key_id.clone())
+        .ok_or_else(|| AppError::NotFound("No encryption key has been set up".to_string()))?;
+    drop(config);
+
+    Ok(EncryptionKey::load(&key_id)?.recovery_key())
+}
+
+/// Adds a key from its recovery key, e.g. to restore on a new device. It
+/// becomes the active key if none is set yet.
+#[tauri::command]
+pub async fn import_recovery_key(
+    state: State<'_, AppState>,
+    recovery_key: String,
+) -> AppResult<String> {
+    let key = EncryptionKey::from_recovery_key(&recovery_key)?;
+    key.store()?;
+
+    let key_id = key.key_id();
+    let mut config = state.config.lock()?;
+    if !config.encryption_key_ids.contains(&key_id) {
+        config.encryption_key_ids.push(key_id.clone());
+    }
+    if config.encryption_key_id.is_none() {
+        config.encryption_key_id = Some(key_id.clone());
+    }
+    save_config_file(&config)?;
+
+    Ok(key_id)
+}
+
+/// Runs Argon2 on a blocking thread rather than the async runtime.
+async fn derive_passphrase_key(passphrase: String) -> AppResult<EncryptionKey> {
+    let key = tokio::task::spawn_blocking(move || EncryptionKey::from_passphrase(&passphrase))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))??;
+    Ok(key)
+}
+
+fn install_encryption_key(config: &mut Config, key: EncryptionKey) -> AppResult<EncryptionKey> {
+    key.store()?;
+    let key_id = key.key_id();
+    if !config.encryption_key_ids.contains(&key_id) {
+        config.encryption_key_ids.push(key_id.clone());
     }
+    config.encryption_key_id = Some(key_id);
+    Ok(key)
 }
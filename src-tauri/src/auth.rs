@@ -0,0 +1,148 @@
+use crate::api::KEYRING_SERVICE;
+use crate::client::{ApiClient, ApiError, DeviceResponse};
+use chrono::{DateTime, Duration, Utc};
+use keyring::Entry;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+const KEYRING_USER: &str = "device-token";
+
+/// Tokens are refreshed once they are this close to expiring.
+const REFRESH_AHEAD_DAYS: i64 = 3;
+
+/// Held while refreshing, so concurrent requests that all hit a 401 don't
+/// each refresh (and invalidate) the token.
+static REFRESH_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+/// The device token and its expiry, stored together in the keyring.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub token: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl StoredToken {
+    fn expires_soon(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at - Utc::now() < Duration::days(REFRESH_AHEAD_DAYS))
+    }
+}
+
+#[derive(Debug)]
+pub enum RefreshError {
+    /// The server turned the refresh down; the device has been signed out.
+    Rejected(String),
+    /// The server couldn't be reached; the current token is kept.
+    Unavailable(String),
+}
+
+impl std::fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::Rejected(e) => write!(f, "Signed out: {}", e),
+            RefreshError::Unavailable(e) => write!(f, "Couldn't refresh the device token: {}", e),
+        }
+    }
+}
+
+fn entry() -> Result<Entry, String> {
+    Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(|e| format!("Keyring error: {}", e))
+}
+
+/// The stored token. Tokens saved before expiries were recorded are kept as
+/// a bare string and have no known expiry.
+pub fn load() -> Option<StoredToken> {
+    let stored = entry().ok()?.get_password().ok()?;
+    Some(serde_json::from_str(&stored).unwrap_or(StoredToken {
+        token: stored,
+        expires_at: None,
+    }))
+}
+
+pub fn store(response: &DeviceResponse) -> Result<StoredToken, String> {
+    let expires_at = match DateTime::parse_from_rfc3339(&response.expires_at) {
+        Ok(expires_at) => Some(expires_at.with_timezone(&Utc)),
+        Err(e) => {
+            log::warn!("Unreadable token expiry {:?}: {}", response.expires_at, e);
+            None
+        }
+    };
+    let stored = StoredToken {
+        token: response.token.clone(),
+        expires_at,
+    };
+    let value = serde_json::to_string(&stored).map_err(|e| e.to_string())?;
+    entry()?
+        .set_password(&value)
+        .map_err(|e| format!("Failed to store token: {}", e))?;
+    Ok(stored)
+}
+
+pub fn clear() {
+    if let Ok(entry) = entry() {
+        entry.delete_password().ok();
+    }
+}
+
+/// Swaps `stale` for a new token. If another request already replaced it,
+/// that token is returned instead of refreshing again. A rejected refresh
+/// signs the device out.
+pub async fn refresh(client: &ApiClient, stale: &str) -> Result<StoredToken, RefreshError> {
+    let _guard = REFRESH_LOCK.lock().await;
+    let current = load().ok_or_else(|| RefreshError::Rejected("Not authenticated".to_string()))?;
+    if current.token != stale {
+        return Ok(current);
+    }
+
+    let device_response = match client.refresh_token(&current.token).await {
+        Ok(device_response) => device_response,
+        Err(ApiError::Auth(e)) => {
+            log::warn!("Token refresh rejected ({}); signing out", e.status);
+            clear();
+            return Err(RefreshError::Rejected(e.message));
+        }
+        Err(e) => return Err(RefreshError::Unavailable(e.to_string())),
+    };
+    let stored = store(&device_response).map_err(RefreshError::Unavailable)?;
+    log::info!("Refreshed device token");
+    Ok(stored)
+}
+
+/// A token to use right now, refreshed first if it's about to expire. If
+/// the refresh can't reach the server the current token is still returned.
+pub async fn valid_token(client: &ApiClient) -> Option<String> {
+    usable_token(client).await.map(|usable| usable.token)
+}
+
+/// A token from [`usable_token`].
+pub struct UsableToken {
+    pub token: String,
+    /// Why refreshing the token just failed, if it did.
+    pub refresh_error: Option<String>,
+}
+
+/// Like [`valid_token`], but also tells whether a refresh was just tried
+/// and failed, so callers don't immediately try again.
+pub async fn usable_token(client: &ApiClient) -> Option<UsableToken> {
+    let stored = load()?;
+    if !stored.expires_soon() {
+        return Some(UsableToken {
+            token: stored.token,
+            refresh_error: None,
+        });
+    }
+    match refresh(client, &stored.token).await {
+        Ok(fresh) => Some(UsableToken {
+            token: fresh.token,
+            refresh_error: None,
+        }),
+        Err(RefreshError::Rejected(_)) => None,
+        Err(e) => {
+            log::warn!("{}; using the current token until it can be refreshed", e);
+            Some(UsableToken {
+                token: stored.token,
+                refresh_error: Some(e.to_string()),
+            })
+        }
+    }
+}
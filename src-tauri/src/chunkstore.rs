@@ -0,0 +1,529 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Files are split into chunks of this size, so a large save that changes in
+/// place only stores the chunks that differ.
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// A new pack is started once the current one reaches this size.
+const PACK_TARGET_SIZE: u64 = 64 * 1024 * 1024;
+/// Packs are rewritten by `gc` once at least this share of them is garbage.
+const REPACK_THRESHOLD: f64 = 0.5;
+
+const PACK_MAGIC: &[u8; 8] = b"SKPACK01";
+/// SHA-256 of the plain chunk, a flags byte and the stored length.
+const RECORD_HEADER_LEN: u64 = 32 + 1 + 4;
+const FLAG_ZSTD: u8 = 1;
+
+/// Only one store is written at a time; uploads to local targets can run
+/// concurrently.
+static STORE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Describes one stored version and the chunks needed to rebuild it. Plain
+/// zips are stored entry by entry, so unchanged files dedupe across versions
+/// and games; anything else (e.g. an encrypted archive) is stored as is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SnapshotManifest {
+    Zip {
+        entries: Vec<ManifestEntry>,
+    },
+    Raw {
+        size_bytes: u64,
+        chunks: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Compression the entry had in the original zip: "stored", "deflated"
+    /// or "zstd".
+    pub method: String,
+    /// Zip timestamp as year, month, day, hour, minute, second.
+    pub modified: Option<[u16; 6]>,
+    pub unix_mode: Option<u32>,
+    pub size_bytes: u64,
+    pub sha256: String,
+    pub chunks: Vec<String>,
+}
+
+impl SnapshotManifest {
+    pub fn chunks(&self) -> impl Iterator<Item = &String> {
+        let (entries, raw): (&[ManifestEntry], &[String]) = match self {
+            SnapshotManifest::Zip { entries } => (entries, &[]),
+            SnapshotManifest::Raw { chunks, .. } => (&[], chunks),
+        };
+        entries.iter().flat_map(|e| &e.chunks).chain(raw)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub checked_at: DateTime<Utc>,
+    pub chunks_checked: u64,
+    /// Chunks whose contents no longer match their hash.
+    pub corrupt_chunks: Vec<String>,
+    /// Snapshots that reference chunks which are missing or corrupt.
+    pub damaged_snapshots: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GcReport {
+    pub chunks_removed: u64,
+    pub bytes_freed: u64,
+    pub packs_rewritten: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChunkLocation {
+    pack: u32,
+    /// Offset of the record header in the pack.
+    offset: u64,
+    stored_len: u32,
+    flags: u8,
+}
+
+impl ChunkLocation {
+    fn record_len(&self) -> u64 {
+        RECORD_HEADER_LEN + self.stored_len as u64
+    }
+}
+
+/// Content-addressed store of file chunks, kept as append-only pack files:
+/// `<dir>/packs/<number>.pack`. Each pack is a magic header followed by
+/// records of `sha256 | flags | length (u32 BE) | data`, with data
+/// zstd-compressed when that makes it smaller. There is no separate index;
+/// it is rebuilt by walking the record headers when the store is opened.
+pub struct ChunkStore {
+    dir: PathBuf,
+    index: HashMap<String, ChunkLocation>,
+    writer: Option<(u32, File, u64)>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl ChunkStore {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let lock = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        fs::create_dir_all(dir.join("packs"))?;
+        let mut store = ChunkStore {
+            dir: dir.to_path_buf(),
+            index: HashMap::new(),
+            writer: None,
+            _lock: lock,
+        };
+        for pack in store.pack_numbers()? {
+            store.scan_pack(pack)?;
+        }
+        Ok(store)
+    }
+
+    fn pack_path(&self, pack: u32) -> PathBuf {
+        self.dir.join("packs").join(format!("{:08}.pack", pack))
+    }
+
+    fn pack_numbers(&self) -> io::Result<Vec<u32>> {
+        let mut packs: Vec<u32> = fs::read_dir(self.dir.join("packs"))?
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_suffix(".pack")?.parse().ok()
+            })
+            .collect();
+        packs.sort_unstable();
+        Ok(packs)
+    }
+
+    /// Indexes a pack's records. A record cut short by a crash is truncated
+    /// away so the pack can be appended to again.
+    fn scan_pack(&mut self, pack: u32) -> io::Result<()> {
+        let path = self.pack_path(pack);
+        let mut file = File::open(&path)?;
+        let len = file.metadata()?.len();
+
+        let mut magic = [0u8; 8];
+        if len < PACK_MAGIC.len() as u64
+            || file.read_exact(&mut magic).is_err()
+            || &magic != PACK_MAGIC
+        {
+            log::warn!("Ignoring unreadable chunk pack {}", path.display());
+            return Ok(());
+        }
+
+        let mut offset = PACK_MAGIC.len() as u64;
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        while offset + RECORD_HEADER_LEN <= len {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut header)?;
+            let location = ChunkLocation {
+                pack,
+                offset,
+                flags: header[32],
+                stored_len: u32::from_be_bytes([header[33], header[34], header[35], header[36]]),
+            };
+            if offset + location.record_len() > len {
+                break;
+            }
+            self.index
+                .entry(hex::encode(&header[..32]))
+                .or_insert(location);
+            offset += location.record_len();
+        }
+
+        if offset < len {
+            log::warn!(
+                "Truncating incomplete record at the end of {}",
+                path.display()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(offset)?;
+        }
+        Ok(())
+    }
+
+    /// Stores `data` unless a chunk with the same hash already exists and
+    /// returns its hash.
+    pub fn put_chunk(&mut self, data: &[u8]) -> io::Result<String> {
+        let hash = Sha256::digest(data);
+        let key = hex::encode(hash);
+        if self.index.contains_key(&key) {
+            return Ok(key);
+        }
+
+        let compressed = zstd::bulk::compress(data, 3)?;
+        let (flags, stored) = if compressed.len() < data.len() {
+            (FLAG_ZSTD, compressed.as_slice())
+        } else {
+            (0, data)
+        };
+        let stored_len = u32::try_from(stored.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Chunk too large"))?;
+
+        let (pack, file, size) = self.writer_for(RECORD_HEADER_LEN + stored.len() as u64)?;
+        let offset = *size;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + stored.len());
+        record.extend_from_slice(&hash);
+        record.push(flags);
+        record.extend_from_slice(&stored_len.to_be_bytes());
+        record.extend_from_slice(stored);
+        if let Err(e) = file.write_all(&record) {
+            // Don't leave a partial record for the next one to follow.
+            file.set_len(offset).ok();
+            self.writer = None;
+            return Err(e);
+        }
+        *size += record.len() as u64;
+
+        let location = ChunkLocation {
+            pack: *pack,
+            offset,
+            stored_len,
+            flags,
+        };
+        self.index.insert(key.clone(), location);
+        Ok(key)
+    }
+
+    /// The pack to append a record of `record_len` bytes to: the last pack
+    /// if it has room, otherwise a new one.
+    fn writer_for(&mut self, record_len: u64) -> io::Result<&mut (u32, File, u64)> {
+        let full = self
+            .writer
+            .as_ref()
+            .is_some_and(|(_, _, size)| *size + record_len > PACK_TARGET_SIZE);
+        if full {
+            self.start_pack()?;
+        }
+        if self.writer.is_none() {
+            match self.pack_numbers()?.last().copied() {
+                Some(pack)
+                    if fs::metadata(self.pack_path(pack))?.len() + record_len
+                        <= PACK_TARGET_SIZE =>
+                {
+                    let file = OpenOptions::new().append(true).open(self.pack_path(pack))?;
+                    let size = file.metadata()?.len();
+                    self.writer = Some((pack, file, size));
+                }
+                _ => self.start_pack()?,
+            }
+        }
+        Ok(self.writer.as_mut().expect("a pack is open"))
+    }
+
+    /// Finishes the current pack and opens a new, empty one after the last.
+    fn start_pack(&mut self) -> io::Result<()> {
+        self.flush()?;
+        let pack = self.pack_numbers()?.last().map_or(1, |p| p + 1);
+        let mut file = File::create(self.pack_path(pack))?;
+        file.write_all(PACK_MAGIC)?;
+        self.writer = Some((pack, file, PACK_MAGIC.len() as u64));
+        Ok(())
+    }
+
+    /// Makes everything written so far durable. Call before writing a
+    /// manifest that refers to the new chunks.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some((_, file, _)) = &mut self.writer {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_chunk(&self, hash: &str) -> io::Result<Vec<u8>> {
+        let location = self.index.get(hash).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Chunk {} is missing", hash),
+            )
+        })?;
+        let data = self.read_record(location)?;
+        if hex::encode(Sha256::digest(&data)) != hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk {} is corrupt", hash),
+            ));
+        }
+        Ok(data)
+    }
+
+    fn read_record(&self, location: &ChunkLocation) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.pack_path(location.pack))?;
+        file.seek(SeekFrom::Start(location.offset + RECORD_HEADER_LEN))?;
+        let mut stored = vec![0u8; location.stored_len as usize];
+        file.read_exact(&mut stored)?;
+        if location.flags & FLAG_ZSTD != 0 {
+            zstd::stream::decode_all(stored.as_slice())
+        } else {
+            Ok(stored)
+        }
+    }
+
+    /// Splits everything `reader` yields into chunks. Returns the chunk
+    /// hashes, the total size and the SHA-256 of the whole stream.
+    fn put_stream(&mut self, mut reader: impl Read) -> io::Result<(Vec<String>, u64, String)> {
+        let mut chunks = Vec::new();
+        let mut total = 0u64;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let mut filled = 0;
+            while filled < CHUNK_SIZE {
+                match reader.read(&mut buffer[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            if filled == 0 {
+                break;
+            }
+            hasher.update(&buffer[..filled]);
+            chunks.push(self.put_chunk(&buffer[..filled])?);
+            total += filled as u64;
+            if filled < CHUNK_SIZE {
+                break;
+            }
+        }
+        Ok((chunks, total, hex::encode(hasher.finalize())))
+    }
+
+    /// Adds an archive to the store. Plain zips are split into their entries;
+    /// anything else is chunked as a whole.
+    pub fn store_archive(&mut self, archive: &Path) -> io::Result<SnapshotManifest> {
+        let manifest = match ZipArchive::new(File::open(archive)?) {
+            Ok(mut zip) => {
+                let mut entries = Vec::with_capacity(zip.len());
+                for index in 0..zip.len() {
+                    let entry = zip.by_index(index)?;
+                    let name = entry.name().to_string();
+                    let is_dir = entry.is_dir();
+                    let method = match entry.compression() {
+                        CompressionMethod::Stored => "stored",
+                        CompressionMethod::Zstd => "zstd",
+                        _ => "deflated",
+                    };
+                    let modified = entry.last_modified();
+                    let modified = [
+                        modified.year(),
+                        modified.month() as u16,
+                        modified.day() as u16,
+                        modified.hour() as u16,
+                        modified.minute() as u16,
+                        modified.second() as u16,
+                    ];
+                    let unix_mode = entry.unix_mode();
+                    let (chunks, size_bytes, sha256) = self.put_stream(entry)?;
+                    entries.push(ManifestEntry {
+                        name,
+                        is_dir,
+                        method: method.to_string(),
+                        modified: Some(modified),
+                        unix_mode,
+                        size_bytes,
+                        sha256,
+                        chunks,
+                    });
+                }
+                SnapshotManifest::Zip { entries }
+            }
+            Err(_) => {
+                let (chunks, size_bytes, _) = self.put_stream(File::open(archive)?)?;
+                SnapshotManifest::Raw { size_bytes, chunks }
+            }
+        };
+        self.flush()?;
+        Ok(manifest)
+    }
+
+    /// Writes the archive described by `manifest` to `dest`. Every chunk is
+    /// checked against its hash on the way. Rebuilt zips hold the same
+    /// entries as the original but aren't byte-identical to it.
+    pub fn rebuild(&self, manifest: &SnapshotManifest, dest: &Path) -> io::Result<()> {
+        let mut output = File::create(dest)?;
+        match manifest {
+            SnapshotManifest::Raw { chunks, .. } => {
+                for hash in chunks {
+                    output.write_all(&self.read_chunk(hash)?)?;
+                }
+            }
+            SnapshotManifest::Zip { entries } => {
+                let mut zip = ZipWriter::new(output);
+                for entry in entries {
+                    let mut options =
+                        FileOptions::default().compression_method(match entry.method.as_str() {
+                            "stored" => CompressionMethod::Stored,
+                            "zstd" => CompressionMethod::Zstd,
+                            _ => CompressionMethod::Deflated,
+                        });
+                    if let Some(mode) = entry.unix_mode {
+                        options = options.unix_permissions(mode);
+                    }
+                    if let Some([year, month, day, hour, minute, second]) = entry.modified {
+                        if let Ok(modified) = zip::DateTime::from_date_and_time(
+                            year,
+                            month as u8,
+                            day as u8,
+                            hour as u8,
+                            minute as u8,
+                            second as u8,
+                        ) {
+                            options = options.last_modified_time(modified);
+                        }
+                    }
+                    if entry.is_dir {
+                        zip.add_directory(&entry.name, options)?;
+                        continue;
+                    }
+                    zip.start_file(&entry.name, options)?;
+                    for hash in &entry.chunks {
+                        zip.write_all(&self.read_chunk(hash)?)?;
+                    }
+                }
+                output = zip.finish()?;
+            }
+        }
+        output.sync_all()
+    }
+
+    /// Reads back every chunk in the store and checks it against its hash,
+    /// then reports which of the given snapshots can't be rebuilt.
+    pub fn verify(&self, snapshots: &[(String, SnapshotManifest)]) -> VerifyReport {
+        let mut corrupt: HashSet<&str> = HashSet::new();
+        for (hash, location) in &self.index {
+            let intact = self
+                .read_record(location)
+                .is_ok_and(|data| hex::encode(Sha256::digest(&data)) == *hash);
+            if !intact {
+                corrupt.insert(hash);
+            }
+        }
+
+        let damaged_snapshots = snapshots
+            .iter()
+            .filter(|(_, manifest)| {
+                manifest
+                    .chunks()
+                    .any(|hash| !self.index.contains_key(hash) || corrupt.contains(hash.as_str()))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut corrupt_chunks: Vec<String> = corrupt.into_iter().map(str::to_string).collect();
+        corrupt_chunks.sort();
+
+        VerifyReport {
+            checked_at: Utc::now(),
+            chunks_checked: self.index.len() as u64,
+            corrupt_chunks,
+            damaged_snapshots,
+        }
+    }
+
+    /// Drops chunks no snapshot refers to. Packs without any live chunk are
+    /// deleted; packs that are mostly garbage have their live chunks copied
+    /// into a new pack first.
+    pub fn gc(&mut self, snapshots: &[SnapshotManifest]) -> io::Result<GcReport> {
+        let live: HashSet<&String> = snapshots.iter().flat_map(|m| m.chunks()).collect();
+        self.flush()?;
+        self.writer = None;
+
+        // Live chunks are copied into packs created after this list was
+        // taken, so a pack being rewritten is never appended to.
+        let mut report = GcReport::default();
+        for pack in self.pack_numbers()? {
+            let path = self.pack_path(pack);
+            let pack_len = fs::metadata(&path)?.len();
+            let records: Vec<(String, ChunkLocation)> = self
+                .index
+                .iter()
+                .filter(|(_, location)| location.pack == pack)
+                .map(|(hash, location)| (hash.clone(), *location))
+                .collect();
+            let (keep, dead): (Vec<_>, Vec<_>) = records
+                .into_iter()
+                .partition(|(hash, _)| live.contains(hash));
+            let dead_bytes: u64 = dead.iter().map(|(_, l)| l.record_len()).sum();
+            if dead.is_empty()
+                || (!keep.is_empty() && (dead_bytes as f64) < pack_len as f64 * REPACK_THRESHOLD)
+            {
+                continue;
+            }
+
+            if !keep.is_empty() && self.writer.is_none() {
+                self.start_pack()?;
+            }
+            for (hash, location) in &keep {
+                let data = self.read_record(location)?;
+                self.index.remove(hash);
+                self.put_chunk(&data)?;
+            }
+            self.flush()?;
+            for (hash, _) in &dead {
+                self.index.remove(hash);
+            }
+            fs::remove_file(&path)?;
+
+            report.chunks_removed += dead.len() as u64;
+            report.bytes_freed += dead_bytes;
+            report.packs_rewritten += 1;
+        }
+
+        if report.chunks_removed > 0 {
+            log::info!(
+                "Removed {} unused chunk(s), freeing {} bytes",
+                report.chunks_removed,
+                report.bytes_freed
+            );
+        }
+        Ok(report)
+    }
+}
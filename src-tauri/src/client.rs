@@ -0,0 +1,781 @@
+use crate::auth::{self, RefreshError};
+use crate::config::{ApiClientSettings, Config};
+use crate::mapping::BackupMapping;
+use crate::progress::ProgressReporter;
+use crate::scanner::StoreIds;
+use crate::throttle::{throttled_file_stream, BandwidthLimiter};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use once_cell::sync::Lazy;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Body, Method, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long an upload or download may go without moving any bytes before
+/// it is given up on. Long enough for a slow bandwidth limit to pass one
+/// chunk.
+pub const TRANSFER_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Shared by every request so connections are pooled; rebuilt when the
+/// settings it was built with change.
+static HTTP: Lazy<Mutex<Option<(ApiClientSettings, reqwest::Client)>>> =
+    Lazy::new(|| Mutex::new(None));
+
+fn user_agent(settings: &ApiClientSettings) -> String {
+    settings.user_agent.clone().unwrap_or_else(|| {
+        format!(
+            "SaveKnight-Desktop/{} ({}; {})",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        )
+    })
+}
+
+/// The shared HTTP client for `settings`. Clones share one connection pool.
+pub fn http_client(settings: &ApiClientSettings) -> reqwest::Client {
+    let mut shared = HTTP.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((current, client)) = shared.as_ref() {
+        if current == settings {
+            return client.clone();
+        }
+    }
+    let client = reqwest::Client::builder()
+        .user_agent(user_agent(settings))
+        .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+        .build()
+        .unwrap_or_else(|e| {
+            log::warn!("Failed to configure the HTTP client, using defaults: {}", e);
+            reqwest::Client::new()
+        });
+    *shared = Some((settings.clone(), client.clone()));
+    client
+}
+
+/// The shared HTTP client as last configured, for requests to services
+/// other than SaveKnight.
+pub fn shared_http_client() -> reqwest::Client {
+    let settings = HTTP
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|(settings, _)| settings.clone())
+        .unwrap_or_default();
+    http_client(&settings)
+}
+
+/// An error response: its status, the server's error code if it sent one,
+/// and a message to show.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorBody {
+    pub status: u16,
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ApiError {
+    /// The server couldn't be reached or the connection dropped.
+    #[error("Couldn't reach SaveKnight: {0}")]
+    Network(String),
+    /// Not signed in, or the device token was refused.
+    #[error("{0}")]
+    Auth(ErrorBody),
+    /// The account is out of storage or over its plan's limits.
+    #[error("{0}")]
+    Quota(ErrorBody),
+    /// The server turned the request down as sent, or accepted it and sent
+    /// back something unreadable. Retrying could repeat what it already did.
+    #[error("{0}")]
+    Validation(ErrorBody),
+    /// The server failed; worth retrying.
+    #[error("{0}")]
+    Server(ErrorBody),
+    /// A file to upload couldn't be read.
+    #[error("{0}")]
+    Local(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ApiError::Auth(body)
+            | ApiError::Quota(body)
+            | ApiError::Validation(body)
+            | ApiError::Server(body) => Some(body.status),
+            ApiError::Network(_) | ApiError::Local(_) => None,
+        }
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ApiError::Auth(body)
+            | ApiError::Quota(body)
+            | ApiError::Validation(body)
+            | ApiError::Server(body) => body.code.as_deref(),
+            ApiError::Network(_) | ApiError::Local(_) => None,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ApiError::Network(_) | ApiError::Server(_))
+    }
+
+    fn signed_out(message: String) -> Self {
+        ApiError::Auth(ErrorBody {
+            status: StatusCode::UNAUTHORIZED.as_u16(),
+            code: None,
+            message,
+        })
+    }
+
+    fn from_status(status: StatusCode, body: ErrorBody) -> Self {
+        if body.code.as_deref() == Some("quota_exceeded") {
+            return ApiError::Quota(body);
+        }
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ApiError::Auth(body),
+            StatusCode::PAYMENT_REQUIRED
+            | StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::INSUFFICIENT_STORAGE => ApiError::Quota(body),
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => ApiError::Server(body),
+            status if status.is_server_error() => ApiError::Server(body),
+            _ => ApiError::Validation(body),
+        }
+    }
+
+    /// Reads an error response. Servers send either a plain message or
+    /// JSON with an `error` code and a `message` or `error_description`.
+    async fn from_response(response: Response) -> Self {
+        #[derive(Deserialize)]
+        struct RawError {
+            error: Option<String>,
+            error_description: Option<String>,
+            message: Option<String>,
+        }
+
+        let status = response.status();
+        let text = response.text().await.unwrap_or_default();
+        let (code, message) = match serde_json::from_str::<RawError>(&text) {
+            Ok(raw) => {
+                let message = raw
+                    .error_description
+                    .or(raw.message)
+                    .or_else(|| raw.error.clone());
+                (raw.error, message)
+            }
+            Err(_) => (None, None),
+        };
+        let message = message
+            .or_else(|| Some(text.trim().to_string()).filter(|t| !t.is_empty()))
+            .unwrap_or_else(|| format!("Server returned {}", status));
+
+        Self::from_status(
+            status,
+            ErrorBody {
+                status: status.as_u16(),
+                code,
+                message,
+            },
+        )
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    /// Bodies are only decoded once the status said the request worked, so a
+    /// decode error means the server may well have done what was asked, such
+    /// as creating a version. That isn't retried.
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            ApiError::Validation(ErrorBody {
+                status: e.status().map(|s| s.as_u16()).unwrap_or(200),
+                code: None,
+                message: format!("Unexpected response from SaveKnight: {}", e),
+            })
+        } else {
+            ApiError::Network(e.to_string())
+        }
+    }
+}
+
+/// Fields the server records about a device when it signs in.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceRegistration {
+    pub device_name: String,
+    pub machine_id: String,
+    pub device_type: String,
+    pub os: String,
+    pub os_version: Option<String>,
+    pub arch: String,
+    pub hostname: Option<String>,
+}
+
+/// Response of the token and refresh endpoints.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceResponse {
+    pub device_id: String,
+    pub token: String,
+    pub expires_at: String,
+}
+
+/// A request for a device token, by authorization code or device code.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenRequest<'a> {
+    pub grant_type: &'a str,
+    pub client_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_verifier: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_uri: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_code: Option<&'a str>,
+    #[serde(flatten)]
+    pub device: &'a DeviceRegistration,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceCodeResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: i64,
+    #[serde(default = "default_poll_interval")]
+    pub interval: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeResponse {
+    pub device: DeviceSummary,
+    pub user: UserInfo,
+    pub subscription: SubscriptionInfo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceSummary {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub id: String,
+    pub email: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionInfo {
+    pub plan_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameProfile {
+    pub id: String,
+    pub name: String,
+    pub platform: String,
+    #[serde(default)]
+    pub store_ids: StoreIds,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewGameProfile<'a> {
+    pub name: &'a str,
+    pub platform: &'a str,
+    #[serde(skip_serializing_if = "StoreIds::is_empty")]
+    pub store_ids: StoreIds,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Capabilities {
+    #[serde(default)]
+    pub delta_uploads: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveVersion {
+    pub id: String,
+    pub version_number: i32,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    #[serde(default)]
+    pub checksum: Option<String>,
+    #[serde(default)]
+    pub slot_name: Option<String>,
+    #[serde(default)]
+    pub device_name: Option<String>,
+    #[serde(default)]
+    pub key_id: Option<String>,
+    #[serde(default)]
+    pub mapping: Option<BackupMapping>,
+}
+
+/// Older servers ignore the paging parameters and return every version.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum VersionsResponse {
+    Page {
+        versions: Vec<SaveVersion>,
+        #[serde(default)]
+        total: Option<u64>,
+        #[serde(default)]
+        has_more: Option<bool>,
+    },
+    All(Vec<SaveVersion>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadResponse {
+    pub success: bool,
+    pub save_version: Option<SaveVersion>,
+    pub upload_id: Option<String>,
+}
+
+/// The form fields sent with an archive.
+#[derive(Debug)]
+pub struct UploadRequest<'a> {
+    pub slot_name: String,
+    pub local_path: &'a str,
+    pub checksum: &'a str,
+    pub mapping: Option<&'a BackupMapping>,
+    pub key_id: Option<&'a str>,
+    pub file_name: String,
+    pub mime: &'a str,
+}
+
+/// A file sent as a request body, throttled and counted as it's read.
+pub struct UploadSource<'a> {
+    pub path: &'a Path,
+    pub size_bytes: u64,
+    pub limiter: Option<Arc<BandwidthLimiter>>,
+    pub progress: &'a ProgressReporter,
+}
+
+impl UploadSource<'_> {
+    /// The file as a request body that reports its progress, and marks
+    /// `activity` whenever a chunk goes out.
+    async fn body(&self, activity: &Activity) -> Result<Body, ApiError> {
+        let reporter = self.progress.clone();
+        let activity = activity.clone();
+        let stream = throttled_file_stream(self.path, self.limiter.clone())
+            .await
+            .map_err(|e| ApiError::Local(format!("Couldn't read {}: {}", self.path.display(), e)))?
+            .inspect_ok(move |chunk| {
+                activity.touch();
+                reporter.bytes_sent(chunk.len() as u64)
+            });
+        Ok(Body::wrap_stream(stream))
+    }
+}
+
+/// When an upload last moved any bytes.
+#[derive(Clone)]
+struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn touch(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Resolves once nothing has been sent for `idle`.
+    async fn stalled(&self, idle: Duration) {
+        loop {
+            let deadline = *self.0.lock().unwrap_or_else(|e| e.into_inner()) + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+}
+
+/// Sends an upload, giving up once its body has stopped going out and the
+/// server hasn't answered for [`TRANSFER_IDLE_TIMEOUT`]. Hyper stops
+/// polling the body when the server stops reading, so a timeout on the
+/// body's chunks alone wouldn't notice.
+async fn send_upload(request: RequestBuilder, activity: &Activity) -> Result<Response, ApiError> {
+    tokio::select! {
+        response = request.send() => Ok(response?),
+        _ = activity.stalled(TRANSFER_IDLE_TIMEOUT) => Err(stalled()),
+    }
+}
+
+fn stalled() -> ApiError {
+    ApiError::Network(format!(
+        "The transfer stalled: nothing was sent or received for {} seconds",
+        TRANSFER_IDLE_TIMEOUT.as_secs()
+    ))
+}
+
+/// The file manifest that starts a delta upload.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeltaUploadRequest<'a> {
+    pub slot_name: String,
+    pub local_path: &'a str,
+    pub checksum: &'a str,
+    pub mapping: &'a BackupMapping,
+    pub files: Vec<DeltaFile<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeltaFile<'a> {
+    pub name: &'a str,
+    pub sha256: &'a str,
+    pub size: u64,
+}
+
+/// Reply to a delta upload manifest: the session to upload blobs into and
+/// the hashes the server already has.
+#[derive(Debug, Deserialize)]
+pub struct DeltaSession {
+    pub upload_id: String,
+    #[serde(default)]
+    pub present: Vec<String>,
+}
+
+/// The SaveKnight API. Calls that need the device token load it on first
+/// use and refresh it once if the server answers 401.
+pub struct ApiClient {
+    http: reqwest::Client,
+    base_url: String,
+    request_timeout: Duration,
+    token: Mutex<Option<String>>,
+}
+
+impl ApiClient {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            http: http_client(&config.api_client),
+            base_url: config.api_url.trim_end_matches('/').to_string(),
+            request_timeout: Duration::from_secs(config.api_client.request_timeout_secs),
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Uses `token` instead of loading one from the keyring.
+    pub fn with_token(self, token: &str) -> Self {
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(token.to_string());
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// An API call, bounded by the request timeout.
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}{}", self.base_url, path))
+            .timeout(self.request_timeout)
+    }
+
+    /// An upload or download, which takes as long as it keeps moving. The
+    /// callers bound it with [`TRANSFER_IDLE_TIMEOUT`] instead.
+    fn transfer(&self, method: Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, format!("{}{}", self.base_url, path))
+    }
+
+    /// The token to send, and why refreshing it failed if that was just
+    /// tried.
+    async fn token(&self) -> Result<(String, Option<String>), ApiError> {
+        if let Some(token) = self.token.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+            return Ok((token, None));
+        }
+        let usable = auth::usable_token(self)
+            .await
+            .ok_or_else(|| ApiError::signed_out("Not authenticated".to_string()))?;
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(usable.token.clone());
+        Ok((usable.token, usable.refresh_error))
+    }
+
+    /// Runs `request` with the device token, refreshing the token and
+    /// running it again on a 401, unless a refresh already failed while
+    /// getting the token. If the refresh is rejected the device is signed
+    /// out.
+    async fn authorized<F, Fut>(&self, request: F) -> Result<Response, ApiError>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<Response, ApiError>>,
+    {
+        let (token, refresh_error) = self.token().await?;
+        let mut response = request(token.clone()).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            if let Some(e) = refresh_error {
+                return Err(ApiError::Network(e));
+            }
+            let fresh = match auth::refresh(self, &token).await {
+                Ok(fresh) => fresh.token,
+                Err(RefreshError::Rejected(e)) => {
+                    return Err(ApiError::signed_out(format!(
+                        "Signed out of SaveKnight, sign in again to continue: {}",
+                        e
+                    )))
+                }
+                Err(RefreshError::Unavailable(e)) => return Err(ApiError::Network(e)),
+            };
+            *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(fresh.clone());
+            response = request(fresh).await?;
+        }
+        check(response).await
+    }
+
+    /// [`Self::authorized`] for requests without a streamed body.
+    async fn send<F>(&self, build: F) -> Result<Response, ApiError>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        self.authorized(|token| {
+            let request = build().bearer_auth(token);
+            async move { Ok(request.send().await?) }
+        })
+        .await
+    }
+
+    pub async fn me(&self) -> Result<MeResponse, ApiError> {
+        let response = self
+            .send(|| self.request(Method::GET, "/api/devices/me"))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn game_profiles(&self) -> Result<Vec<GameProfile>, ApiError> {
+        let response = self
+            .send(|| self.request(Method::GET, "/api/devices/game-profiles"))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn create_game_profile(
+        &self,
+        profile: &NewGameProfile<'_>,
+    ) -> Result<GameProfile, ApiError> {
+        let response = self
+            .send(|| {
+                self.request(Method::POST, "/api/devices/game-profiles")
+                    .json(profile)
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn capabilities(&self) -> Result<Capabilities, ApiError> {
+        let response = self
+            .send(|| self.request(Method::GET, "/api/devices/capabilities"))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// One page of a game profile's versions, newest first.
+    pub async fn versions(
+        &self,
+        profile_id: &str,
+        page: u32,
+        page_size: u32,
+    ) -> Result<VersionsResponse, ApiError> {
+        let path = format!("/api/devices/game-profiles/{}/versions", profile_id);
+        let response = self
+            .send(|| {
+                self.request(Method::GET, &path)
+                    .query(&[("page", page), ("pageSize", page_size)])
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Uploads a whole archive as a new version.
+    pub async fn upload_version(
+        &self,
+        profile_id: &str,
+        upload: &UploadRequest<'_>,
+        source: &UploadSource<'_>,
+    ) -> Result<UploadResponse, ApiError> {
+        let path = format!("/api/devices/upload/{}", profile_id);
+        let mapping = match upload.mapping {
+            Some(mapping) => {
+                Some(serde_json::to_string(mapping).map_err(|e| ApiError::Local(e.to_string()))?)
+            }
+            None => None,
+        };
+
+        // The form is rebuilt for a retry, since its file stream can only be
+        // read once.
+        let response = self
+            .authorized(|token| {
+                let path = &path;
+                let mapping = mapping.clone();
+                async move {
+                    let activity = Activity::new();
+                    let body = source.body(&activity).await?;
+                    source.progress.upload_started(source.size_bytes);
+
+                    let mut form = Form::new()
+                        .text("slotName", upload.slot_name.clone())
+                        .text("localPath", upload.local_path.to_string())
+                        .text("checksum", upload.checksum.to_string());
+                    if let Some(mapping) = mapping {
+                        form = form.text("mapping", mapping);
+                    }
+                    if let Some(key_id) = upload.key_id {
+                        form = form
+                            .text("encrypted", "true")
+                            .text("keyId", key_id.to_string());
+                    }
+                    let part = Part::stream_with_length(body, source.size_bytes)
+                        .file_name(upload.file_name.clone())
+                        .mime_str(upload.mime)?;
+                    let form = form.part("saveFile", part);
+
+                    let request = self
+                        .transfer(Method::POST, path)
+                        .bearer_auth(token)
+                        .multipart(form);
+                    send_upload(request, &activity).await
+                }
+            })
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Sends the file manifest for a delta upload.
+    pub async fn start_delta_upload(
+        &self,
+        profile_id: &str,
+        request: &DeltaUploadRequest<'_>,
+    ) -> Result<DeltaSession, ApiError> {
+        let path = format!("/api/devices/upload/{}/delta", profile_id);
+        let response = self
+            .send(|| self.request(Method::POST, &path).json(request))
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    /// Uploads one file the server is missing.
+    pub async fn put_delta_blob(
+        &self,
+        upload_id: &str,
+        sha256: &str,
+        source: &UploadSource<'_>,
+    ) -> Result<(), ApiError> {
+        let path = format!("/api/devices/upload/delta/{}/blobs/{}", upload_id, sha256);
+        self.authorized(|token| {
+            let path = &path;
+            async move {
+                let activity = Activity::new();
+                let body = source.body(&activity).await?;
+                let request = self
+                    .transfer(Method::PUT, path)
+                    .bearer_auth(token)
+                    .header("Content-Type", "application/octet-stream")
+                    .header("Content-Length", source.size_bytes)
+                    .body(body);
+                send_upload(request, &activity).await
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Creates the version once all of a delta upload's files are there.
+    pub async fn commit_delta_upload(
+        &self,
+        profile_id: &str,
+        upload_id: &str,
+    ) -> Result<UploadResponse, ApiError> {
+        let path = format!(
+            "/api/devices/upload/{}/delta/{}/commit",
+            profile_id, upload_id
+        );
+        let response = self.send(|| self.request(Method::POST, &path)).await?;
+        Ok(response.json().await?)
+    }
+
+    /// The archive of a version, to be streamed by the caller, who bounds
+    /// each read with [`TRANSFER_IDLE_TIMEOUT`].
+    pub async fn download_version(&self, version_id: &str) -> Result<Response, ApiError> {
+        let path = format!("/api/devices/versions/{}/download", version_id);
+        tokio::time::timeout(
+            TRANSFER_IDLE_TIMEOUT,
+            self.send(|| self.transfer(Method::GET, &path)),
+        )
+        .await
+        .map_err(|_| stalled())?
+    }
+
+    pub async fn delete_version(&self, version_id: &str) -> Result<(), ApiError> {
+        let path = format!("/api/devices/versions/{}", version_id);
+        self.send(|| self.request(Method::DELETE, &path)).await?;
+        Ok(())
+    }
+
+    /// Swaps `token` for a new one. Doesn't go through the refresh logic,
+    /// since this is that logic's request.
+    pub async fn refresh_token(&self, token: &str) -> Result<DeviceResponse, ApiError> {
+        let response = self
+            .request(Method::POST, "/api/devices/refresh-token")
+            .bearer_auth(token)
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    /// Trades an authorization code or approved device code for a token.
+    pub async fn request_token(
+        &self,
+        request: &TokenRequest<'_>,
+    ) -> Result<DeviceResponse, ApiError> {
+        let response = self
+            .request(Method::POST, "/api/devices/token")
+            .json(request)
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    pub async fn request_device_code(
+        &self,
+        client_id: &str,
+    ) -> Result<DeviceCodeResponse, ApiError> {
+        let response = self
+            .request(Method::POST, "/api/devices/device-code")
+            .json(&serde_json::json!({ "clientId": client_id }))
+            .send()
+            .await?;
+        Ok(check(response).await?.json().await?)
+    }
+}
+
+async fn check(response: Response) -> Result<Response, ApiError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(ApiError::from_response(response).await)
+    }
+}
@@ -0,0 +1,77 @@
+use crate::config::{CompressionMethod, CompressionSettings};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use zip::write::FileOptions;
+
+/// Formats that are already compressed and gain nothing from another pass.
+const INCOMPRESSIBLE_EXTENSIONS: &[&str] = &[
+    "7z", "avif", "br", "bz2", "cab", "flac", "gz", "jpeg", "jpg", "lz4", "lzma", "m4a", "mkv",
+    "mp3", "mp4", "ogg", "opus", "pak", "png", "rar", "webm", "webp", "xz", "zip", "zst",
+];
+
+/// Files smaller than this are not worth probing.
+const PROBE_MIN_SIZE: usize = 4 * 1024;
+const PROBE_SAMPLE_SIZE: usize = 64 * 1024;
+/// A sample that doesn't shrink below this ratio is stored uncompressed.
+const PROBE_MAX_RATIO: f64 = 0.95;
+
+const DEFLATE_LEVELS: RangeInclusive<i32> = 0..=9;
+const ZSTD_LEVELS: RangeInclusive<i32> = -7..=22;
+
+pub fn validate(settings: &CompressionSettings) -> Result<(), String> {
+    let range = match settings.method {
+        CompressionMethod::Stored => return Ok(()),
+        CompressionMethod::Deflate => DEFLATE_LEVELS,
+        CompressionMethod::Zstd => ZSTD_LEVELS,
+    };
+    match settings.level {
+        Some(level) if !range.contains(&level) => Err(format!(
+            "Compression level {} is out of range {}..={} for {:?}",
+            level,
+            range.start(),
+            range.end(),
+            settings.method
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Picks the zip options for one file. With `auto` enabled, files that look
+/// incompressible are stored as-is.
+pub fn file_options(settings: &CompressionSettings, path: &Path, data: &[u8]) -> FileOptions {
+    let options = FileOptions::default().unix_permissions(0o644);
+
+    let method = if settings.auto && is_incompressible(path, data) {
+        CompressionMethod::Stored
+    } else {
+        settings.method
+    };
+
+    match method {
+        CompressionMethod::Stored => options.compression_method(zip::CompressionMethod::Stored),
+        CompressionMethod::Deflate => options
+            .compression_method(zip::CompressionMethod::Deflated)
+            .compression_level(settings.level),
+        CompressionMethod::Zstd => options
+            .compression_method(zip::CompressionMethod::Zstd)
+            .compression_level(settings.level),
+    }
+}
+
+fn is_incompressible(path: &Path, data: &[u8]) -> bool {
+    let known_format = path.extension().is_some_and(|ext| {
+        INCOMPRESSIBLE_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+    });
+    if known_format {
+        return true;
+    }
+    if data.len() < PROBE_MIN_SIZE {
+        return false;
+    }
+
+    let sample = &data[..data.len().min(PROBE_SAMPLE_SIZE)];
+    match zstd::bulk::compress(sample, 1) {
+        Ok(compressed) => compressed.len() as f64 / sample.len() as f64 > PROBE_MAX_RATIO,
+        Err(_) => false,
+    }
+}
@@ -1,8 +1,10 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub api_url: String,
     pub device_id: Option<String>,
@@ -10,6 +12,73 @@ pub struct Config {
     pub scan_interval_minutes: u32,
     pub enabled_games: Vec<String>,
     pub custom_paths: Vec<CustomPath>,
+    pub upload_concurrency: u32,
+    pub bandwidth_limit_bytes_per_sec: Option<u64>,
+    pub bandwidth_limit_window: Option<TimeWindow>,
+    pub encryption_enabled: bool,
+    pub encryption_key_id: Option<String>,
+    pub encryption_key_ids: Vec<String>,
+    pub compression: CompressionSettings,
+    pub game_settings: HashMap<String, GameSettings>,
+    /// SaveKnight game profile ID of each game, by game name. Filled in on a
+    /// game's first backup; change an entry to back the game up elsewhere.
+    pub game_profiles: HashMap<String, String>,
+    /// Where backups are sent. Every target receives every version.
+    pub storage_targets: Vec<StorageTarget>,
+    /// Safety snapshots kept per game; one is taken before every restore.
+    pub safety_snapshots_to_keep: u32,
+    /// Applied to the original paths in a backup before restoring, for saves
+    /// that can't be placed automatically.
+    pub restore_redirects: Vec<RedirectRule>,
+    /// Which versions local storage targets keep; pruning runs after every
+    /// backup. Remote targets manage their own retention.
+    pub local_retention: RetentionPolicy,
+    pub api_client: ApiClientSettings,
+}
+
+/// Connection settings for the SaveKnight API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ApiClientSettings {
+    pub connect_timeout_secs: u64,
+    /// Limit for API calls; uploads and downloads aren't bounded by it.
+    pub request_timeout_secs: u64,
+    /// Replaces the default `SaveKnight-Desktop/<version> (<os>; <arch>)`.
+    pub user_agent: Option<String>,
+}
+
+impl Default for ApiClientSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 10,
+            request_timeout_secs: 30,
+            user_agent: None,
+        }
+    }
+}
+
+/// Restores files from below `source` (a path prefix on the machine that
+/// made the backup) into `target` on this machine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RedirectRule {
+    pub source: String,
+    pub target: String,
+}
+
+/// Versions to keep per game. A version survives if any of the count rules
+/// keeps it; with none set, all are kept. `max_total_bytes` then drops the
+/// oldest survivors until the rest fit, always keeping the newest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// The newest N versions.
+    pub keep_last: Option<u32>,
+    /// The newest version of each of the last N days, weeks and months that
+    /// have any.
+    pub daily: Option<u32>,
+    pub weekly: Option<u32>,
+    pub monthly: Option<u32>,
+    pub max_total_bytes: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +87,103 @@ pub struct CustomPath {
     pub path: String,
 }
 
+/// Local time range in `HH:MM` form. A range whose end is before its start
+/// wraps around midnight.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeWindow {
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionMethod {
+    Stored,
+    Deflate,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressionSettings {
+    pub method: CompressionMethod,
+    /// Method-specific level; `None` uses the method's default.
+    pub level: Option<i32>,
+    /// Store files that are already compressed instead of recompressing them.
+    pub auto: bool,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self {
+            method: CompressionMethod::Deflate,
+            level: None,
+            auto: true,
+        }
+    }
+}
+
+/// Per-game overrides of the global settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameSettings {
+    pub compression: Option<CompressionSettings>,
+    pub retention: Option<RetentionPolicy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StorageTarget {
+    #[serde(rename = "saveknight")]
+    SaveKnight,
+    /// A local or network folder. With `dedup`, files shared between
+    /// versions and games are stored once in a chunk store.
+    Local {
+        name: String,
+        path: String,
+        #[serde(default)]
+        dedup: bool,
+    },
+    /// Any S3-compatible service (AWS, MinIO, Garage, R2). The secret access
+    /// key is kept in the keyring.
+    S3 {
+        name: String,
+        endpoint: String,
+        #[serde(default = "default_s3_region")]
+        region: String,
+        bucket: String,
+        #[serde(default)]
+        prefix: String,
+        access_key_id: String,
+        #[serde(default)]
+        path_style: bool,
+    },
+    /// A WebDAV server such as Nextcloud. The password is kept in the keyring.
+    #[serde(rename = "webdav")]
+    WebDav {
+        name: String,
+        url: String,
+        username: String,
+        #[serde(default)]
+        folder: String,
+    },
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+impl StorageTarget {
+    pub fn id(&self) -> String {
+        match self {
+            StorageTarget::SaveKnight => "saveknight".to_string(),
+            StorageTarget::Local { name, .. } => format!("local:{}", name),
+            StorageTarget::S3 { name, .. } => format!("s3:{}", name),
+            StorageTarget::WebDav { name, .. } => format!("webdav:{}", name),
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
@@ -27,6 +193,20 @@ impl Default for Config {
             scan_interval_minutes: 60,
             enabled_games: Vec::new(),
             custom_paths: Vec::new(),
+            upload_concurrency: 3,
+            bandwidth_limit_bytes_per_sec: None,
+            bandwidth_limit_window: None,
+            encryption_enabled: false,
+            encryption_key_id: None,
+            encryption_key_ids: Vec::new(),
+            compression: CompressionSettings::default(),
+            game_settings: HashMap::new(),
+            game_profiles: HashMap::new(),
+            storage_targets: vec![StorageTarget::SaveKnight],
+            safety_snapshots_to_keep: 5,
+            restore_redirects: Vec::new(),
+            local_retention: RetentionPolicy::default(),
+            api_client: ApiClientSettings::default(),
         }
     }
 }
@@ -40,6 +220,27 @@ impl Config {
         path
     }
 
+    pub fn data_dir() -> PathBuf {
+        let mut path = dirs::data_local_dir().unwrap_or_else(|| PathBuf::from("."));
+        path.push("SaveKnight");
+        fs::create_dir_all(&path).ok();
+        path
+    }
+
+    pub fn compression_for(&self, game_name: &str) -> CompressionSettings {
+        self.game_settings
+            .get(game_name)
+            .and_then(|settings| settings.compression.clone())
+            .unwrap_or_else(|| self.compression.clone())
+    }
+
+    pub fn retention_for(&self, game_name: &str) -> RetentionPolicy {
+        self.game_settings
+            .get(game_name)
+            .and_then(|settings| settings.retention.clone())
+            .unwrap_or_else(|| self.local_retention.clone())
+    }
+
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
         let path = Self::config_path();
         if path.exists() {
@@ -0,0 +1,390 @@
+use crate::api::KEYRING_SERVICE;
+use crate::mapping::EncryptionInfo;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use argon2::Argon2;
+use keyring::Entry;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub const ALGORITHM: &str = "aes-256-gcm-stream";
+
+/// Archives from before the header was authenticated along with each chunk.
+const MAGIC_V1: &[u8; 6] = b"SKENC1";
+const MAGIC: &[u8; 6] = b"SKENC2";
+const MIN_PASSPHRASE_CHARS: usize = 8;
+const CHUNK_SIZE: usize = 64 * 1024;
+const SALT_LEN: usize = 16;
+const NONCE_PREFIX_LEN: usize = 7;
+const KEY_ID_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + KEY_ID_LEN + NONCE_PREFIX_LEN;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("Passphrase must be at least {} characters", MIN_PASSPHRASE_CHARS)]
+    WeakPassphrase,
+    #[error("{0}")]
+    InvalidRecoveryKey(&'static str),
+    /// Not in the keyring; it has to be imported from its recovery key.
+    #[error("Encryption key {0} is not available on this device")]
+    KeyNotAvailable(String),
+    /// Not in the keyring, but it can be re-derived from its passphrase.
+    #[error("Encryption key {0} is not available on this device; enter its passphrase to restore")]
+    PassphraseRequired(String),
+    #[error("The passphrase doesn't match encryption key {0}")]
+    WrongPassphrase(String),
+    #[error("Archive was encrypted with key {0}")]
+    WrongKey(String),
+    #[error("Archive is not encrypted")]
+    NotEncrypted,
+    #[error("Decryption failed: the archive is corrupt or has been tampered with")]
+    Corrupt,
+    #[error("Encryption failed")]
+    EncryptionFailed,
+    #[error("Key derivation failed: {0}")]
+    KeyDerivation(String),
+    #[error("Keyring error: {0}")]
+    Keyring(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    Random = 0,
+    Passphrase = 1,
+}
+
+/// A 256-bit archive key. Random keys only live in the keyring and can be
+/// brought back with their recovery key; passphrase keys can also be
+/// re-derived from the passphrase and the salt in each archive header, see
+/// [`key_for_archive`].
+pub struct EncryptionKey {
+    bytes: [u8; 32],
+    source: KeySource,
+    salt: [u8; SALT_LEN],
+}
+
+impl EncryptionKey {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        Self {
+            bytes,
+            source: KeySource::Random,
+            salt: [0; SALT_LEN],
+        }
+    }
+
+    /// Derives a new key with a random salt. Argon2 is slow on purpose, so
+    /// call this off the async runtime.
+    pub fn from_passphrase(passphrase: &str) -> Result<Self, CryptoError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::from_passphrase_with_salt(passphrase, salt)
+    }
+
+    /// Derives the key a passphrase and salt were used for before, e.g. with
+    /// the salt read from an archive header.
+    pub fn from_passphrase_with_salt(
+        passphrase: &str,
+        salt: [u8; SALT_LEN],
+    ) -> Result<Self, CryptoError> {
+        if passphrase.chars().count() < MIN_PASSPHRASE_CHARS {
+            return Err(CryptoError::WeakPassphrase);
+        }
+        let mut bytes = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut bytes)
+            .map_err(|e| CryptoError::KeyDerivation(e.to_string()))?;
+        Ok(Self {
+            bytes,
+            source: KeySource::Passphrase,
+            salt,
+        })
+    }
+
+    /// Parses a recovery key as produced by [`EncryptionKey::recovery_key`].
+    /// Dashes and whitespace are ignored.
+    pub fn from_recovery_key(recovery_key: &str) -> Result<Self, CryptoError> {
+        let cleaned: String = recovery_key
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect();
+        let decoded = hex::decode(&cleaned)
+            .map_err(|_| CryptoError::InvalidRecoveryKey("Invalid recovery key"))?;
+        let bytes: [u8; 32] = decoded
+            .try_into()
+            .map_err(|_| CryptoError::InvalidRecoveryKey("Recovery key has the wrong length"))?;
+        Ok(Self {
+            bytes,
+            source: KeySource::Random,
+            salt: [0; SALT_LEN],
+        })
+    }
+
+    /// Short identifier derived from the key itself, so any copy of the key
+    /// can be matched to the archives it encrypted.
+    pub fn key_id(&self) -> String {
+        hex::encode(self.key_id_bytes())
+    }
+
+    fn key_id_bytes(&self) -> [u8; KEY_ID_LEN] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"saveknight-key-id");
+        hasher.update(self.bytes);
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(&hasher.finalize()[..KEY_ID_LEN]);
+        key_id
+    }
+
+    /// Printable form of the raw key, in groups of four hex digits.
+    pub fn recovery_key(&self) -> String {
+        hex::encode(self.bytes)
+            .as_bytes()
+            .chunks(4)
+            .map(|c| String::from_utf8_lossy(c).to_uppercase())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    pub fn info(&self) -> EncryptionInfo {
+        EncryptionInfo {
+            algorithm: ALGORITHM.to_string(),
+            key_id: self.key_id(),
+        }
+    }
+
+    fn keyring_entry(key_id: &str) -> Result<Entry, CryptoError> {
+        Entry::new(KEYRING_SERVICE, &format!("encryption-key:{}", key_id))
+            .map_err(|e| CryptoError::Keyring(e.to_string()))
+    }
+
+    /// Stores the key in the keyring under its key ID.
+    pub fn store(&self) -> Result<(), CryptoError> {
+        let value = format!(
+            "{}:{}:{}",
+            self.source as u8,
+            hex::encode(self.salt),
+            hex::encode(self.bytes)
+        );
+        Self::keyring_entry(&self.key_id())?
+            .set_password(&value)
+            .map_err(|e| CryptoError::Keyring(format!("Failed to store encryption key: {}", e)))
+    }
+
+    pub fn load(key_id: &str) -> Result<Self, CryptoError> {
+        let value = match Self::keyring_entry(key_id)?.get_password() {
+            Ok(value) => value,
+            Err(keyring::Error::NoEntry) => {
+                return Err(CryptoError::KeyNotAvailable(key_id.to_string()))
+            }
+            Err(e) => return Err(CryptoError::Keyring(e.to_string())),
+        };
+        let corrupt = || CryptoError::Keyring("Corrupt encryption key in keyring".to_string());
+
+        let mut parts = value.splitn(3, ':');
+        let source = match parts.next() {
+            Some("1") => KeySource::Passphrase,
+            _ => KeySource::Random,
+        };
+        let salt = parts
+            .next()
+            .and_then(|s| hex::decode(s).ok())
+            .and_then(|s| <[u8; SALT_LEN]>::try_from(s).ok())
+            .ok_or_else(corrupt)?;
+        let bytes = parts
+            .next()
+            .and_then(|s| hex::decode(s).ok())
+            .and_then(|s| <[u8; 32]>::try_from(s).ok())
+            .ok_or_else(corrupt)?;
+
+        Ok(Self {
+            bytes,
+            source,
+            salt,
+        })
+    }
+}
+
+/// Encrypts `input` into `output` with the STREAM construction over
+/// AES-256-GCM, so each 64 KiB chunk is authenticated and truncation or
+/// reordering is detected.
+///
+/// Layout: magic, key source, salt, key ID, nonce prefix, then the
+/// encrypted chunks. The header is the associated data of every chunk, so
+/// it can't be swapped for another archive's.
+pub fn encrypt_file(input: &Path, output: &Path, key: &EncryptionKey) -> Result<(), CryptoError> {
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut nonce_prefix);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(key.source as u8);
+    header.extend_from_slice(&key.salt);
+    header.extend_from_slice(&key.key_id_bytes());
+    header.extend_from_slice(&nonce_prefix);
+
+    let mut reader = File::open(input)?;
+    let mut writer = File::create(output)?;
+    writer.write_all(&header)?;
+
+    let cipher = Aes256Gcm::new(&key.bytes.into());
+    let mut encryptor = EncryptorBE32::from_aead(cipher, nonce_prefix.as_slice().into());
+
+    let mut current = read_chunk(&mut reader, CHUNK_SIZE)?;
+    loop {
+        let next = read_chunk(&mut reader, CHUNK_SIZE)?;
+        let payload = Payload {
+            msg: &current,
+            aad: &header,
+        };
+        if next.is_empty() {
+            let ciphertext = encryptor
+                .encrypt_last(payload)
+                .map_err(|_| CryptoError::EncryptionFailed)?;
+            writer.write_all(&ciphertext)?;
+            break;
+        }
+        let ciphertext = encryptor
+            .encrypt_next(payload)
+            .map_err(|_| CryptoError::EncryptionFailed)?;
+        writer.write_all(&ciphertext)?;
+        current = next;
+    }
+
+    writer.sync_all()?;
+    Ok(())
+}
+
+/// Decrypts a file written by [`encrypt_file`]. Fails if `key` isn't the key
+/// the archive was encrypted with or if any chunk fails authentication.
+pub fn decrypt_file(input: &Path, output: &Path, key: &EncryptionKey) -> Result<(), CryptoError> {
+    let mut reader = File::open(input)?;
+    let header = read_header(&mut reader)?.ok_or(CryptoError::NotEncrypted)?;
+    let key_id = hex::encode(header.key_id);
+    if key_id != key.key_id() {
+        return Err(CryptoError::WrongKey(key_id));
+    }
+
+    let mut writer = File::create(output)?;
+    let cipher = Aes256Gcm::new(&key.bytes.into());
+    let mut decryptor = DecryptorBE32::from_aead(cipher, header.nonce_prefix.as_slice().into());
+
+    let mut current = read_chunk(&mut reader, CHUNK_SIZE + TAG_LEN)?;
+    loop {
+        let next = read_chunk(&mut reader, CHUNK_SIZE + TAG_LEN)?;
+        let payload = Payload {
+            msg: &current,
+            aad: &header.aad,
+        };
+        if next.is_empty() {
+            let plaintext = decryptor
+                .decrypt_last(payload)
+                .map_err(|_| CryptoError::Corrupt)?;
+            writer.write_all(&plaintext)?;
+            break;
+        }
+        let plaintext = decryptor
+            .decrypt_next(payload)
+            .map_err(|_| CryptoError::Corrupt)?;
+        writer.write_all(&plaintext)?;
+        current = next;
+    }
+
+    writer.sync_all()?;
+    Ok(())
+}
+
+/// ID of the key an archive was encrypted with, or `None` if the file isn't
+/// an encrypted archive.
+pub fn archive_key_id(path: &Path) -> io::Result<Option<String>> {
+    let mut reader = File::open(path)?;
+    Ok(read_header(&mut reader)?.map(|header| hex::encode(header.key_id)))
+}
+
+/// The key an encrypted archive needs. It comes from the keyring or, for a
+/// passphrase key that isn't there, e.g. on a new device, is re-derived from
+/// `passphrase` and the salt in the archive header and then stored.
+pub fn key_for_archive(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<EncryptionKey, CryptoError> {
+    let header = read_header(&mut File::open(path)?)?.ok_or(CryptoError::NotEncrypted)?;
+    let key_id = hex::encode(header.key_id);
+    match EncryptionKey::load(&key_id) {
+        Err(CryptoError::KeyNotAvailable(_)) if header.source == KeySource::Passphrase => {
+            match passphrase {
+                Some(passphrase) => import_passphrase_key(&header, passphrase),
+                None => Err(CryptoError::PassphraseRequired(key_id)),
+            }
+        }
+        result => result,
+    }
+}
+
+fn import_passphrase_key(header: &Header, passphrase: &str) -> Result<EncryptionKey, CryptoError> {
+    let key = EncryptionKey::from_passphrase_with_salt(passphrase, header.salt)?;
+    if key.key_id_bytes() != header.key_id {
+        return Err(CryptoError::WrongPassphrase(hex::encode(header.key_id)));
+    }
+    key.store()?;
+    log::info!(
+        "Re-derived encryption key {} from its passphrase",
+        key.key_id()
+    );
+    Ok(key)
+}
+
+struct Header {
+    source: KeySource,
+    salt: [u8; SALT_LEN],
+    key_id: [u8; KEY_ID_LEN],
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    /// Associated data of every chunk: the raw header, or nothing for
+    /// archives written before it was authenticated.
+    aad: Vec<u8>,
+}
+
+fn read_header(reader: &mut impl Read) -> io::Result<Option<Header>> {
+    let header = read_chunk(reader, HEADER_LEN)?;
+    if header.len() < HEADER_LEN {
+        return Ok(None);
+    }
+    let aad = match &header[..MAGIC.len()] {
+        magic if magic == MAGIC => header.clone(),
+        magic if magic == MAGIC_V1 => Vec::new(),
+        _ => return Ok(None),
+    };
+
+    let salt_start = MAGIC.len() + 1;
+    let key_id_start = salt_start + SALT_LEN;
+    let nonce_start = key_id_start + KEY_ID_LEN;
+    let mut salt = [0u8; SALT_LEN];
+    salt.copy_from_slice(&header[salt_start..key_id_start]);
+    let mut key_id = [0u8; KEY_ID_LEN];
+    key_id.copy_from_slice(&header[key_id_start..nonce_start]);
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    nonce_prefix.copy_from_slice(&header[nonce_start..]);
+    Ok(Some(Header {
+        source: match header[MAGIC.len()] {
+            1 => KeySource::Passphrase,
+            _ => KeySource::Random,
+        },
+        salt,
+        key_id,
+        nonce_prefix,
+        aad,
+    }))
+}
+
+fn read_chunk(reader: &mut impl Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(size);
+    reader.take(size as u64).read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
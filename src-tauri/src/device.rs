@@ -0,0 +1,164 @@
+use crate::client::DeviceRegistration;
+use crate::config::Config;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::process::Command;
+
+/// Written to the data directory when the OS doesn't expose a machine ID.
+const FALLBACK_ID_FILE: &str = "machine-id";
+
+/// Mixed into the hash so the ID sent to the server can't be matched against
+/// the raw OS identifier or another app's hash of it.
+const MACHINE_ID_SALT: &str = "saveknight-device:";
+
+/// What the server is told about this machine when it signs in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MachineInfo {
+    pub machine_id: String,
+    pub os: String,
+    pub os_version: Option<String>,
+    pub arch: String,
+    pub hostname: Option<String>,
+}
+
+impl MachineInfo {
+    pub fn current() -> Self {
+        Self {
+            machine_id: machine_id(),
+            os: std::env::consts::OS.to_string(),
+            os_version: os_version(),
+            arch: std::env::consts::ARCH.to_string(),
+            hostname: hostname(),
+        }
+    }
+
+    /// Registration fields sent along with a sign-in.
+    pub fn registration(&self, device_name: &str) -> DeviceRegistration {
+        DeviceRegistration {
+            device_name: device_name.to_string(),
+            machine_id: self.machine_id.clone(),
+            device_type: self.os.clone(),
+            os: self.os.clone(),
+            os_version: self.os_version.clone(),
+            arch: self.arch.clone(),
+            hostname: self.hostname.clone(),
+        }
+    }
+}
+
+/// A stable ID for this machine, hashed so the raw identifier never leaves
+/// it.
+pub fn machine_id() -> String {
+    let raw = os_machine_id().unwrap_or_else(fallback_machine_id);
+    hex::encode(Sha256::digest(format!("{}{}", MACHINE_ID_SALT, raw)))
+}
+
+/// Rejects empty IDs and the all-zero or all-F placeholders some firmware
+/// reports.
+fn usable(id: String) -> Option<String> {
+    let id = id.trim().to_string();
+    let placeholder = id
+        .chars()
+        .filter(|c| *c != '-')
+        .all(|c| c == '0' || c.eq_ignore_ascii_case(&'f'));
+    (!id.is_empty() && !placeholder).then_some(id)
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+#[cfg(target_os = "linux")]
+fn os_machine_id() -> Option<String> {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok().and_then(usable))
+}
+
+#[cfg(target_os = "macos")]
+fn os_machine_id() -> Option<String> {
+    let output = command_output("ioreg", &["-rd1", "-c", "IOPlatformExpertDevice"])?;
+    output
+        .lines()
+        .find(|line| line.contains("\"IOPlatformUUID\""))
+        .and_then(|line| line.split('"').nth(3))
+        .and_then(|id| usable(id.to_string()))
+}
+
+#[cfg(target_os = "windows")]
+fn os_machine_id() -> Option<String> {
+    command_output(
+        "reg",
+        &[
+            "query",
+            r"HKLM\SOFTWARE\Microsoft\Cryptography",
+            "/v",
+            "MachineGuid",
+        ],
+    )
+    .and_then(|output| {
+        output
+            .lines()
+            .find(|line| line.trim_start().starts_with("MachineGuid"))
+            .and_then(|line| line.split_whitespace().last())
+            .and_then(|id| usable(id.to_string()))
+    })
+    .or_else(|| {
+        command_output("wmic", &["csproduct", "get", "uuid"])
+            .and_then(|output| output.lines().nth(1).and_then(|l| usable(l.to_string())))
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn os_machine_id() -> Option<String> {
+    None
+}
+
+/// A random ID generated on first use and kept in the data directory.
+fn fallback_machine_id() -> String {
+    let path = Config::data_dir().join(FALLBACK_ID_FILE);
+    if let Some(id) = fs::read_to_string(&path).ok().and_then(usable) {
+        return id;
+    }
+    let id = uuid::Uuid::new_v4().to_string();
+    if let Err(e) = fs::write(&path, &id) {
+        log::warn!("Failed to save machine ID to {}: {}", path.display(), e);
+    }
+    id
+}
+
+fn hostname() -> Option<String> {
+    #[cfg(target_os = "windows")]
+    let name = std::env::var("COMPUTERNAME").ok();
+    #[cfg(not(target_os = "windows"))]
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| command_output("hostname", &[]));
+
+    name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty())
+}
+
+fn os_version() -> Option<String> {
+    #[cfg(target_os = "windows")]
+    let version = command_output("cmd", &["/C", "ver"]);
+    #[cfg(target_os = "macos")]
+    let version = command_output("sw_vers", &["-productVersion"]);
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let version = fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|release| {
+            release
+                .lines()
+                .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+                .map(|name| name.trim_matches('"').to_string())
+        });
+
+    version
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
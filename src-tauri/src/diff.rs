@@ -0,0 +1,140 @@
+use crate::config::RedirectRule;
+use crate::mapping::BackupMapping;
+use crate::restore;
+use crate::scanner::LocalSaveFile;
+use crate::uploader::calculate_checksum;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    /// On disk but not in the backup.
+    Added,
+    /// In the backup but no longer on disk.
+    Removed,
+    Modified,
+    Unchanged,
+    /// In the backup, but where it belongs on this machine is unknown.
+    Unresolved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileChange {
+    pub kind: ChangeKind,
+    pub archive_name: Option<String>,
+    /// Path on this machine, if known.
+    pub path: Option<String>,
+    pub backup_size_bytes: Option<u64>,
+    pub local_size_bytes: Option<u64>,
+    /// Local size minus backup size.
+    pub size_delta: Option<i64>,
+    pub backup_modified: Option<DateTime<Utc>>,
+    pub local_modified: Option<DateTime<Utc>>,
+    /// Seconds the local file is newer (positive) or older than the backup.
+    pub modified_delta_secs: Option<i64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionDiff {
+    pub game_name: String,
+    pub version_id: String,
+    pub changes: Vec<FileChange>,
+    pub added: u32,
+    pub removed: u32,
+    pub modified: u32,
+    pub unchanged: u32,
+    pub unresolved: u32,
+}
+
+/// Compares the files on disk with a version's mapping. Each backed-up file
+/// is looked for where a restore would put it; files of the same size are
+/// hashed to tell whether they changed.
+pub fn diff_version(
+    mapping: &BackupMapping,
+    version_id: &str,
+    local_files: &[LocalSaveFile],
+    redirects: &[RedirectRule],
+) -> VersionDiff {
+    let mut local: HashMap<PathBuf, &LocalSaveFile> = local_files
+        .iter()
+        .map(|file| (file.path.clone(), file))
+        .collect();
+    let plan = restore::plan_restore(mapping, redirects);
+
+    let mut changes = Vec::with_capacity(mapping.files.len());
+    for (file, planned) in mapping.files.iter().zip(plan) {
+        let mut change = FileChange {
+            kind: ChangeKind::Unresolved,
+            archive_name: Some(file.archive_name.clone()),
+            path: planned.destination.clone(),
+            backup_size_bytes: Some(file.size_bytes),
+            local_size_bytes: None,
+            size_delta: None,
+            backup_modified: file.modified,
+            local_modified: None,
+            modified_delta_secs: None,
+            error: planned.error,
+        };
+        let Some(destination) = planned.destination.map(PathBuf::from) else {
+            changes.push(change);
+            continue;
+        };
+
+        let Some(local_file) = local.remove(&destination) else {
+            change.kind = ChangeKind::Removed;
+            changes.push(change);
+            continue;
+        };
+        let local_modified = local_file.modified.map(DateTime::<Utc>::from);
+        change.local_size_bytes = Some(local_file.size_bytes);
+        change.size_delta = Some(local_file.size_bytes as i64 - file.size_bytes as i64);
+        change.local_modified = local_modified;
+        change.modified_delta_secs = local_modified
+            .zip(file.modified)
+            .map(|(local, backup)| (local - backup).num_seconds());
+        change.kind = if local_file.size_bytes != file.size_bytes {
+            ChangeKind::Modified
+        } else {
+            match calculate_checksum(&local_file.path) {
+                Ok(hash) if hash.eq_ignore_ascii_case(&file.sha256) => ChangeKind::Unchanged,
+                Ok(_) => ChangeKind::Modified,
+                Err(e) => {
+                    change.error = Some(format!("Couldn't read local file: {}", e));
+                    ChangeKind::Modified
+                }
+            }
+        };
+        changes.push(change);
+    }
+
+    let mut added: Vec<&LocalSaveFile> = local.into_values().collect();
+    added.sort_by(|a, b| a.path.cmp(&b.path));
+    changes.extend(added.into_iter().map(|file| FileChange {
+        kind: ChangeKind::Added,
+        archive_name: None,
+        path: Some(file.path.to_string_lossy().to_string()),
+        backup_size_bytes: None,
+        local_size_bytes: Some(file.size_bytes),
+        size_delta: Some(file.size_bytes as i64),
+        backup_modified: None,
+        local_modified: file.modified.map(DateTime::<Utc>::from),
+        modified_delta_secs: None,
+        error: None,
+    }));
+
+    let count = |kind| changes.iter().filter(|c| c.kind == kind).count() as u32;
+    VersionDiff {
+        game_name: mapping.game_name.clone(),
+        version_id: version_id.to_string(),
+        added: count(ChangeKind::Added),
+        removed: count(ChangeKind::Removed),
+        modified: count(ChangeKind::Modified),
+        unchanged: count(ChangeKind::Unchanged),
+        unresolved: count(ChangeKind::Unresolved),
+        changes,
+    }
+}
@@ -0,0 +1,292 @@
+use crate::client::ApiError;
+use crate::crypto::CryptoError;
+use crate::storage::StorageError;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type AppResult<T> = Result<T, AppError>;
+
+/// Errors returned to the frontend, serialized as `{code, message, details}`.
+/// Codes are stable so the UI can match on them; messages are for people.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("{0}")]
+    NotAuthenticated(String),
+    #[error("{message}")]
+    QuotaExceeded {
+        message: String,
+        status: Option<u16>,
+    },
+    #[error("{}", permission_message(.path))]
+    PermissionDenied { path: Option<PathBuf> },
+    #[error("{0}")]
+    NotFound(String),
+    /// An encryption key isn't on this device. With `passphrase`, it can be
+    /// re-derived by retrying with its passphrase; otherwise its recovery
+    /// key has to be imported.
+    #[error("{message}")]
+    KeyRequired {
+        message: String,
+        key_id: String,
+        passphrase: bool,
+    },
+    /// The request doesn't make sense as sent, e.g. an empty file list.
+    #[error("{0}")]
+    InvalidInput(String),
+    /// Something else is already running that this has to wait for.
+    #[error("{0}")]
+    Busy(String),
+    #[error("{0}")]
+    Network(String),
+    /// The server or storage target failed; trying again later may work.
+    #[error("{message}")]
+    Unavailable {
+        message: String,
+        status: Option<u16>,
+    },
+    /// The server or storage target refused the request.
+    #[error("{message}")]
+    Rejected {
+        message: String,
+        status: Option<u16>,
+    },
+    #[error("{}", io_message(.path, .message))]
+    Io {
+        path: Option<PathBuf>,
+        message: String,
+    },
+    #[error("{0}")]
+    Internal(String),
+    /// Any of the above, about one game.
+    #[error("{game}: {source}")]
+    Game { game: String, source: Box<AppError> },
+}
+
+fn permission_message(path: &Option<PathBuf>) -> String {
+    match path {
+        Some(path) => format!("Permission denied: {}", path.display()),
+        None => "Permission denied".to_string(),
+    }
+}
+
+fn io_message(path: &Option<PathBuf>, message: &str) -> String {
+    match path {
+        Some(path) => format!("{}: {}", path.display(), message),
+        None => message.to_string(),
+    }
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotAuthenticated(_) => "not_authenticated",
+            AppError::QuotaExceeded { .. } => "quota_exceeded",
+            AppError::PermissionDenied { .. } => "permission_denied",
+            AppError::NotFound(_) => "not_found",
+            AppError::KeyRequired { .. } => "key_required",
+            AppError::InvalidInput(_) => "invalid_input",
+            AppError::Busy(_) => "busy",
+            AppError::Network(_) => "network",
+            AppError::Unavailable { .. } => "unavailable",
+            AppError::Rejected { .. } => "rejected",
+            AppError::Io { .. } => "io",
+            AppError::Internal(_) => "internal",
+            AppError::Game { source, .. } => source.code(),
+        }
+    }
+
+    /// An I/O error on `path`.
+    pub fn io(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => AppError::PermissionDenied {
+                path: Some(path.to_path_buf()),
+            },
+            _ => AppError::Io {
+                path: Some(path.to_path_buf()),
+                message: e.to_string(),
+            },
+        }
+    }
+
+    /// Marks the error as being about `game`.
+    pub fn for_game(self, game: &str) -> Self {
+        match self {
+            AppError::Game { .. } => self,
+            source => AppError::Game {
+                game: game.to_string(),
+                source: Box::new(source),
+            },
+        }
+    }
+
+    fn details(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut details = serde_json::Map::new();
+        match self {
+            AppError::QuotaExceeded { status, .. }
+            | AppError::Unavailable { status, .. }
+            | AppError::Rejected { status, .. } => {
+                if let Some(status) = status {
+                    details.insert("status".to_string(), (*status).into());
+                }
+            }
+            AppError::PermissionDenied { path } | AppError::Io { path, .. } => {
+                if let Some(path) = path {
+                    details.insert("path".to_string(), path.display().to_string().into());
+                }
+            }
+            AppError::KeyRequired {
+                key_id, passphrase, ..
+            } => {
+                details.insert("key_id".to_string(), key_id.clone().into());
+                details.insert("passphrase".to_string(), (*passphrase).into());
+            }
+            AppError::Game { game, source } => {
+                details = source.details();
+                details.insert("game".to_string(), game.clone().into());
+            }
+            _ => {}
+        }
+        details
+    }
+}
+
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("AppError", 3)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("details", &self.details())?;
+        state.end()
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => AppError::PermissionDenied { path: None },
+            _ => AppError::Io {
+                path: None,
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for AppError {
+    fn from(e: std::sync::PoisonError<T>) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<ApiError> for AppError {
+    fn from(e: ApiError) -> Self {
+        let status = e.status();
+        match e {
+            ApiError::Auth(body) => AppError::NotAuthenticated(body.message),
+            ApiError::Quota(body) => AppError::QuotaExceeded {
+                message: body.message,
+                status,
+            },
+            ApiError::Validation(body) if body.status == 404 => AppError::NotFound(body.message),
+            ApiError::Validation(body) => AppError::Rejected {
+                message: body.message,
+                status,
+            },
+            ApiError::Server(body) => AppError::Unavailable {
+                message: body.message,
+                status,
+            },
+            ApiError::Network(_) => AppError::Network(e.to_string()),
+            ApiError::Local(message) => AppError::Io {
+                path: None,
+                message,
+            },
+        }
+    }
+}
+
+impl From<StorageError> for AppError {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::Transient(message) => AppError::Unavailable {
+                message,
+                status: None,
+            },
+            StorageError::Rejected(message) => AppError::Rejected {
+                message,
+                status: None,
+            },
+            StorageError::Api(e) => e.into(),
+        }
+    }
+}
+
+impl From<CryptoError> for AppError {
+    fn from(e: CryptoError) -> Self {
+        let message = e.to_string();
+        match e {
+            CryptoError::WeakPassphrase
+            | CryptoError::InvalidRecoveryKey(_)
+            | CryptoError::WrongPassphrase(_)
+            | CryptoError::WrongKey(_)
+            | CryptoError::NotEncrypted => AppError::InvalidInput(message),
+            CryptoError::KeyNotAvailable(key_id) => AppError::KeyRequired {
+                message,
+                key_id,
+                passphrase: false,
+            },
+            CryptoError::PassphraseRequired(key_id) => AppError::KeyRequired {
+                message,
+                key_id,
+                passphrase: true,
+            },
+            CryptoError::Corrupt => AppError::Io {
+                path: None,
+                message,
+            },
+            CryptoError::EncryptionFailed
+            | CryptoError::KeyDerivation(_)
+            | CryptoError::Keyring(_) => AppError::Internal(message),
+            CryptoError::Io(e) => e.into(),
+        }
+    }
+}
+
+/// Keeps the structure of errors that were boxed along the way.
+impl From<Box<dyn std::error::Error + Send + Sync>> for AppError {
+    fn from(e: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        let e = match e.downcast::<AppError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<io::Error>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<CryptoError>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<StorageError>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<ApiError>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<reqwest::Error>() {
+            Ok(e) => return AppError::Network(e.to_string()),
+            Err(e) => e,
+        };
+        // A damaged archive or mapping file.
+        if e.is::<zip::result::ZipError>() || e.is::<serde_json::Error>() {
+            return AppError::Io {
+                path: None,
+                message: e.to_string(),
+            };
+        }
+        AppError::Internal(e.to_string())
+    }
+}
@@ -0,0 +1,307 @@
+use crate::config::Config;
+use crate::error::AppError;
+use crate::queue::UploadJob;
+use crate::scanner::DetectedGame;
+use crate::storage::{GameRef, StoredVersion};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Held while appending, so lines from concurrent writers don't interleave.
+static APPEND_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// What started a backup attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadTrigger {
+    /// The user backed the game up.
+    Manual,
+    /// The user retried queued uploads.
+    Retry,
+    /// The periodic run of the upload queue.
+    Background,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptResult {
+    Succeeded,
+    /// The target couldn't be reached; the upload stays queued.
+    Deferred,
+    Failed,
+}
+
+/// How a successful attempt compares with the versions on its target now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerState {
+    Present,
+    /// Deleted or pruned since.
+    Missing,
+}
+
+/// One backup attempt of one game to one target. The history file only ever
+/// has entries appended to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: String,
+    pub job_id: Option<String>,
+    pub game_name: String,
+    pub game_profile_id: Option<String>,
+    /// `None` when the backup failed before it was queued for any target.
+    pub target: Option<String>,
+    pub attempted_at: DateTime<Utc>,
+    pub size_bytes: u64,
+    pub checksum: Option<String>,
+    pub result: AttemptResult,
+    pub version_id: Option<String>,
+    pub version_number: Option<i32>,
+    pub error: Option<String>,
+    /// The error's code, as in the errors commands return.
+    pub code: Option<String>,
+    pub trigger: UploadTrigger,
+}
+
+impl HistoryEntry {
+    /// An attempt to upload a queued job.
+    pub fn for_job(job: &UploadJob, trigger: UploadTrigger, result: AttemptResult) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            job_id: Some(job.id.clone()),
+            game_name: job.game_name.clone(),
+            game_profile_id: Some(job.game_profile_id.clone()).filter(|id| !id.is_empty()),
+            target: Some(job.target.clone()),
+            attempted_at: Utc::now(),
+            size_bytes: job.size_bytes,
+            checksum: Some(job.checksum.clone()),
+            result,
+            version_id: None,
+            version_number: None,
+            error: None,
+            code: None,
+            trigger,
+        }
+    }
+
+    /// A backup that failed while staging, before any upload was queued.
+    pub fn staging_failed(
+        game: &DetectedGame,
+        game_profile_id: &str,
+        trigger: UploadTrigger,
+        error: &AppError,
+    ) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            job_id: None,
+            game_name: game.name.clone(),
+            game_profile_id: Some(game_profile_id.to_string()).filter(|id| !id.is_empty()),
+            target: None,
+            attempted_at: Utc::now(),
+            size_bytes: game.total_size_bytes,
+            checksum: None,
+            result: AttemptResult::Failed,
+            version_id: None,
+            version_number: None,
+            error: Some(error.to_string()),
+            code: Some(error.code().to_string()),
+            trigger,
+        }
+    }
+
+    pub fn with_version(mut self, version: &StoredVersion) -> Self {
+        self.version_id = Some(version.id.clone());
+        self.version_number = version.version_number;
+        self
+    }
+
+    pub fn with_error(mut self, error: &AppError) -> Self {
+        self.error = Some(error.to_string());
+        self.code = Some(error.code().to_string());
+        self
+    }
+}
+
+/// An entry as returned to the frontend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryItem {
+    #[serde(flatten)]
+    pub entry: HistoryEntry,
+    /// Only set for successful attempts whose target was checked.
+    pub server_state: Option<ServerState>,
+}
+
+/// Entries to return; unset fields match everything.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryFilter {
+    pub game_name: Option<String>,
+    pub target: Option<String>,
+    pub result: Option<AttemptResult>,
+    pub trigger: Option<UploadTrigger>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        let excluded = self
+            .game_name
+            .as_ref()
+            .is_some_and(|g| *g != entry.game_name)
+            || self
+                .target
+                .as_ref()
+                .is_some_and(|t| entry.target.as_ref() != Some(t))
+            || self.result.is_some_and(|r| r != entry.result)
+            || self.trigger.is_some_and(|t| t != entry.trigger)
+            || self.since.is_some_and(|since| entry.attempted_at < since)
+            || self.until.is_some_and(|until| entry.attempted_at >= until);
+        !excluded
+    }
+}
+
+/// One page of the history, newest first. Pages start at 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadHistoryPage {
+    pub entries: Vec<HistoryItem>,
+    pub page: u32,
+    pub page_size: u32,
+    pub total: u64,
+    pub has_more: bool,
+    /// Whether every target on the page could be checked.
+    pub reconciled: bool,
+}
+
+fn history_path() -> PathBuf {
+    Config::data_dir().join("upload-history.jsonl")
+}
+
+/// Appends an entry. Failing to record history never fails the backup.
+pub fn record(entry: &HistoryEntry) {
+    if let Err(e) = append(entry) {
+        log::warn!("Failed to record upload history: {}", e);
+    }
+}
+
+fn append(entry: &HistoryEntry) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    let _guard = APPEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path())?
+        .write_all(line.as_bytes())
+}
+
+/// Every entry, oldest first. Lines that can't be read, such as one cut
+/// short by a crash, are skipped.
+fn load() -> Vec<HistoryEntry> {
+    let content = match fs::read_to_string(history_path()) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            log::warn!("Failed to read upload history: {}", e);
+            return Vec::new();
+        }
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Skipping unreadable upload history entry: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn query(filter: &HistoryFilter, page: u32, page_size: u32) -> UploadHistoryPage {
+    let mut entries: Vec<HistoryEntry> = load().into_iter().filter(|e| filter.matches(e)).collect();
+    entries.reverse();
+    let total = entries.len();
+    let start = (page.max(1) as usize - 1).saturating_mul(page_size as usize);
+    let entries: Vec<HistoryItem> = entries
+        .into_iter()
+        .skip(start)
+        .take(page_size as usize)
+        .map(|entry| HistoryItem {
+            entry,
+            server_state: None,
+        })
+        .collect();
+    UploadHistoryPage {
+        has_more: start + entries.len() < total,
+        entries,
+        page,
+        page_size,
+        total: total as u64,
+        reconciled: false,
+    }
+}
+
+/// The targets and games whose versions are needed to reconcile `items`.
+pub fn reconcile_groups(items: &[HistoryItem]) -> Vec<(String, GameRef)> {
+    let mut groups: Vec<(String, GameRef)> = Vec::new();
+    for item in items {
+        let entry = &item.entry;
+        let target = match (&entry.target, entry.result) {
+            (Some(target), AttemptResult::Succeeded) => target,
+            _ => continue,
+        };
+        let known = groups.iter().any(|(t, game)| {
+            t == target && game.name == entry.game_name && game.profile_id == entry.game_profile_id
+        });
+        if !known {
+            groups.push((
+                target.clone(),
+                GameRef {
+                    name: entry.game_name.clone(),
+                    profile_id: entry.game_profile_id.clone(),
+                },
+            ));
+        }
+    }
+    groups
+}
+
+/// Marks the successful attempts for `target` and `game` as present on or
+/// missing from it, going by `versions`, its current versions. Attempts
+/// without a version ID are matched by checksum.
+pub fn reconcile(
+    items: &mut [HistoryItem],
+    target: &str,
+    game: &GameRef,
+    versions: &[StoredVersion],
+) {
+    for item in items.iter_mut() {
+        let entry = &mut item.entry;
+        if entry.result != AttemptResult::Succeeded
+            || entry.target.as_deref() != Some(target)
+            || entry.game_name != game.name
+            || entry.game_profile_id != game.profile_id
+        {
+            continue;
+        }
+        let version = match &entry.version_id {
+            Some(id) => versions.iter().find(|v| v.id == *id),
+            None => versions
+                .iter()
+                .find(|v| v.checksum.is_some() && v.checksum == entry.checksum),
+        };
+        item.server_state = Some(match version {
+            Some(version) => {
+                entry.version_id = Some(version.id.clone());
+                entry.version_number = entry.version_number.or(version.version_number);
+                ServerState::Present
+            }
+            None => ServerState::Missing,
+        });
+    }
+}
@@ -0,0 +1,545 @@
+use crate::client::{ApiClient, DeviceRegistration, DeviceResponse, TokenRequest};
+use crate::error::{AppError, AppResult};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const CLIENT_ID: &str = "saveknight-desktop";
+
+/// How long the browser sign-in waits for the redirect.
+const BROWSER_LOGIN_TIMEOUT_SECS: u64 = 300;
+
+/// Largest redirect request read from the loopback listener.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// How long one connection to the loopback listener has to send its request,
+/// so a connection that never does can't hold up the real redirect.
+const REQUEST_READ_TIMEOUT_SECS: u64 = 10;
+
+/// How much longer to wait between polls after each `slow_down`.
+const SLOW_DOWN_SECS: u64 = 5;
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// The device-code sign-in in progress, if any.
+static PENDING_DEVICE_LOGIN: Lazy<Mutex<Option<PendingDeviceLogin>>> =
+    Lazy::new(|| Mutex::new(None));
+
+fn random_token(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// PKCE verifier and challenge for one browser sign-in, plus the `state`
+/// the redirect has to echo back.
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+    pub state: String,
+}
+
+impl Pkce {
+    pub fn new() -> Self {
+        let verifier = random_token(32);
+        Self {
+            challenge: s256_challenge(&verifier),
+            verifier,
+            state: random_token(16),
+        }
+    }
+}
+
+/// The S256 code challenge for a verifier.
+fn s256_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Receives the authorization redirect on 127.0.0.1.
+pub struct LoopbackListener {
+    listener: TcpListener,
+    pub redirect_uri: String,
+}
+
+impl LoopbackListener {
+    /// Binds an ephemeral port on the loopback interface.
+    pub async fn bind() -> AppResult<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        Ok(Self {
+            listener,
+            redirect_uri: format!("http://127.0.0.1:{}/callback", port),
+        })
+    }
+
+    /// Waits for the redirect carrying `expected_state` and returns its
+    /// code. Requests for other paths get a 404 and are ignored.
+    pub async fn wait_for_code(&self, expected_state: &str) -> AppResult<String> {
+        let wait = async {
+            loop {
+                let (mut stream, _) = self.listener.accept().await?;
+                let read = tokio::time::timeout(
+                    std::time::Duration::from_secs(REQUEST_READ_TIMEOUT_SECS),
+                    read_request_target(&mut stream),
+                );
+                let target = match read.await {
+                    Ok(Some(target)) => target,
+                    Ok(None) | Err(_) => continue,
+                };
+                let url = match reqwest::Url::parse(&format!("http://127.0.0.1{}", target)) {
+                    Ok(url) if url.path() == "/callback" => url,
+                    _ => {
+                        respond(&mut stream, "404 Not Found", "Not found.").await;
+                        continue;
+                    }
+                };
+
+                let param = |name: &str| {
+                    url.query_pairs()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.into_owned())
+                };
+                if param("state").as_deref() != Some(expected_state) {
+                    respond(
+                        &mut stream,
+                        "400 Bad Request",
+                        "This sign-in link doesn't match the one SaveKnight started.",
+                    )
+                    .await;
+                    continue;
+                }
+                if let Some(error) = param("error") {
+                    let description = param("error_description").unwrap_or_else(|| error.clone());
+                    respond(
+                        &mut stream,
+                        "200 OK",
+                        "Sign-in was cancelled. You can close this tab.",
+                    )
+                    .await;
+                    return Err(AppError::NotAuthenticated(format!(
+                        "Sign-in failed: {}",
+                        description
+                    )));
+                }
+                match param("code") {
+                    Some(code) => {
+                        respond(
+                            &mut stream,
+                            "200 OK",
+                            "Signed in to SaveKnight. You can close this tab and return to the app.",
+                        )
+                        .await;
+                        return Ok(code);
+                    }
+                    None => {
+                        respond(
+                            &mut stream,
+                            "400 Bad Request",
+                            "Missing authorization code.",
+                        )
+                        .await;
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(
+            std::time::Duration::from_secs(BROWSER_LOGIN_TIMEOUT_SECS),
+            wait,
+        )
+        .await
+        .map_err(|_| {
+            AppError::NotAuthenticated("Timed out waiting for the browser sign-in".to_string())
+        })?
+    }
+}
+
+/// The path and query of an HTTP request line, e.g. `/callback?code=...`.
+async fn read_request_target(stream: &mut tokio::net::TcpStream) -> Option<String> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buffer.windows(4).any(|w| w == b"\r\n\r\n") && buffer.len() < MAX_REQUEST_BYTES {
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+    let request = String::from_utf8_lossy(&buffer);
+    let mut parts = request.lines().next()?.split_whitespace();
+    match (parts.next()?, parts.next()?) {
+        ("GET", target) if target.starts_with('/') => Some(target.to_string()),
+        _ => None,
+    }
+}
+
+async fn respond(stream: &mut tokio::net::TcpStream, status: &str, message: &str) {
+    let body = format!(
+        "<!doctype html><html><head><meta charset=\"utf-8\"><title>SaveKnight</title></head>\
+         <body style=\"font-family: sans-serif; text-align: center; padding-top: 4em\">\
+         <p>{}</p></body></html>",
+        message
+    );
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await.ok();
+    stream.shutdown().await.ok();
+}
+
+/// The page the user signs in on.
+pub fn authorize_url(client: &ApiClient, redirect_uri: &str, pkce: &Pkce) -> AppResult<String> {
+    let url = reqwest::Url::parse_with_params(
+        &format!("{}/oauth/authorize", client.base_url()),
+        &[
+            ("response_type", "code"),
+            ("client_id", CLIENT_ID),
+            ("redirect_uri", redirect_uri),
+            ("code_challenge", &pkce.challenge),
+            ("code_challenge_method", "S256"),
+            ("state", &pkce.state),
+        ],
+    )
+    .map_err(|e| AppError::InvalidInput(format!("Invalid API URL: {}", e)))?;
+    Ok(url.into())
+}
+
+/// Trades the redirect's code for a device token.
+pub async fn exchange_code(
+    client: &ApiClient,
+    code: &str,
+    pkce: &Pkce,
+    redirect_uri: &str,
+    device: &DeviceRegistration,
+) -> AppResult<DeviceResponse> {
+    Ok(client
+        .request_token(&TokenRequest {
+            grant_type: "authorization_code",
+            client_id: CLIENT_ID,
+            code: Some(code),
+            code_verifier: Some(&pkce.verifier),
+            redirect_uri: Some(redirect_uri),
+            device_code: None,
+            device,
+        })
+        .await?)
+}
+
+/// What to show the user while a device-code sign-in is pending.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceLoginPrompt {
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+struct PendingDeviceLogin {
+    device_code: String,
+    interval_secs: u64,
+    expires_at: DateTime<Utc>,
+}
+
+/// Starts a device-code sign-in for machines without a usable browser. The
+/// user enters the returned code on another device; `finish_device_login`
+/// then waits for them to approve it.
+pub async fn start_device_login(client: &ApiClient) -> AppResult<DeviceLoginPrompt> {
+    let code = client.request_device_code(CLIENT_ID).await?;
+    let expires_at = Utc::now() + Duration::seconds(code.expires_in);
+
+    *PENDING_DEVICE_LOGIN.lock()? = Some(PendingDeviceLogin {
+        device_code: code.device_code,
+        interval_secs: code.interval.max(1),
+        expires_at,
+    });
+
+    Ok(DeviceLoginPrompt {
+        user_code: code.user_code,
+        verification_uri: code.verification_uri,
+        verification_uri_complete: code.verification_uri_complete,
+        expires_at,
+    })
+}
+
+/// Polls until the pending device-code sign-in is approved, denied or
+/// expires.
+pub async fn finish_device_login(
+    client: &ApiClient,
+    device: &DeviceRegistration,
+) -> AppResult<DeviceResponse> {
+    let (device_code, interval_secs, expires_at) = {
+        let pending = PENDING_DEVICE_LOGIN.lock()?;
+        let pending = pending
+            .as_ref()
+            .ok_or_else(|| AppError::InvalidInput("No sign-in in progress".to_string()))?;
+        (
+            pending.device_code.clone(),
+            pending.interval_secs,
+            pending.expires_at,
+        )
+    };
+
+    let request = TokenRequest {
+        grant_type: DEVICE_CODE_GRANT,
+        client_id: CLIENT_ID,
+        code: None,
+        code_verifier: None,
+        redirect_uri: None,
+        device_code: Some(&device_code),
+        device,
+    };
+
+    let result = poll_for_token(
+        client,
+        &request,
+        std::time::Duration::from_secs(interval_secs),
+        std::time::Duration::from_secs(SLOW_DOWN_SECS),
+        expires_at,
+    )
+    .await;
+
+    let mut pending = PENDING_DEVICE_LOGIN.lock()?;
+    if pending.as_ref().map(|p| &p.device_code) == Some(&device_code) {
+        *pending = None;
+    }
+    result
+}
+
+/// Asks for the token every `interval` until the user approves or denies the
+/// sign-in, it expires or it is cancelled. Each `slow_down` from the server
+/// adds `slow_down` to the interval.
+async fn poll_for_token(
+    client: &ApiClient,
+    request: &TokenRequest<'_>,
+    mut interval: std::time::Duration,
+    slow_down: std::time::Duration,
+    expires_at: DateTime<Utc>,
+) -> AppResult<DeviceResponse> {
+    let device_code = request.device_code.unwrap_or_default();
+    loop {
+        if Utc::now() >= expires_at {
+            return Err(code_expired());
+        }
+        tokio::time::sleep(interval).await;
+        if !is_pending(device_code) {
+            return Err(AppError::NotAuthenticated(
+                "Sign-in was cancelled".to_string(),
+            ));
+        }
+
+        let error = match client.request_token(request).await {
+            Ok(device_response) => return Ok(device_response),
+            Err(e) => e,
+        };
+        match error.code() {
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval += slow_down,
+            Some("access_denied") => {
+                return Err(AppError::NotAuthenticated("Sign-in was denied".to_string()))
+            }
+            Some("expired_token") => return Err(code_expired()),
+            _ if error.is_transient() => log::warn!("Polling for device sign-in failed: {}", error),
+            _ => return Err(error.into()),
+        }
+    }
+}
+
+fn code_expired() -> AppError {
+    AppError::NotAuthenticated(
+        "The sign-in code expired. Start again to get a new one.".to_string(),
+    )
+}
+
+fn is_pending(device_code: &str) -> bool {
+    PENDING_DEVICE_LOGIN
+        .lock()
+        .map(|pending| pending.as_ref().map(|p| p.device_code.as_str()) == Some(device_code))
+        .unwrap_or(false)
+}
+
+/// Abandons the pending device-code sign-in; a waiting
+/// `finish_device_login` returns at its next poll.
+pub fn cancel_device_login() {
+    if let Ok(mut pending) = PENDING_DEVICE_LOGIN.lock() {
+        *pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn s256_challenge_is_base64url_sha256() {
+        assert_eq!(
+            s256_challenge("dBjftJeZ4CVP-mJ92K9UyTwBrMEw5DDRZ-TT9yZ5Gq0vJ6"),
+            "JNN8d16tU-Bgc6p8uG-NIx-GrANP2BGmho607v2iWMM"
+        );
+        let pkce = Pkce::new();
+        assert_eq!(pkce.verifier.len(), 43);
+        assert_eq!(pkce.challenge, s256_challenge(&pkce.verifier));
+    }
+
+    /// Sends a GET for `target` and returns the whole response.
+    async fn get(addr: &str, target: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, addr).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn loopback_ignores_redirects_with_another_state() {
+        let listener = LoopbackListener::bind().await.unwrap();
+        let addr = listener
+            .redirect_uri
+            .trim_start_matches("http://")
+            .trim_end_matches("/callback")
+            .to_string();
+        let wait = tokio::spawn(async move { listener.wait_for_code("expected").await });
+
+        let response = get(&addr, "/callback?code=stolen&state=forged").await;
+        assert!(response.starts_with("HTTP/1.1 400"));
+        assert!(!wait.is_finished());
+
+        let response = get(&addr, "/callback?code=good&state=expected").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(wait.await.unwrap().unwrap(), "good");
+    }
+
+    /// Answers each request with the next of `responses` and returns the
+    /// base URL to reach it on.
+    async fn mock_token_endpoint(responses: Vec<(u16, &'static str)>) -> String {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                read_request(&mut stream).await;
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.ok();
+            }
+        });
+        base_url
+    }
+
+    /// Reads a request's headers and its body, so the client isn't reset.
+    async fn read_request(stream: &mut TcpStream) {
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            request.extend_from_slice(&chunk[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return;
+                }
+            }
+            if read == 0 {
+                return;
+            }
+        }
+    }
+
+    async fn poll(responses: Vec<(u16, &'static str)>) -> AppResult<DeviceResponse> {
+        let config = Config {
+            api_url: mock_token_endpoint(responses).await,
+            ..Config::default()
+        };
+        let client = ApiClient::new(&config);
+        let device = DeviceRegistration {
+            device_name: "Test".to_string(),
+            machine_id: "machine".to_string(),
+            device_type: "desktop".to_string(),
+            os: "linux".to_string(),
+            os_version: None,
+            arch: "x86_64".to_string(),
+            hostname: None,
+        };
+        let expires_at = Utc::now() + Duration::seconds(60);
+        *PENDING_DEVICE_LOGIN.lock().unwrap() = Some(PendingDeviceLogin {
+            device_code: "device-code".to_string(),
+            interval_secs: 1,
+            expires_at,
+        });
+        let request = TokenRequest {
+            grant_type: DEVICE_CODE_GRANT,
+            client_id: CLIENT_ID,
+            code: None,
+            code_verifier: None,
+            redirect_uri: None,
+            device_code: Some("device-code"),
+            device: &device,
+        };
+        let step = std::time::Duration::from_millis(10);
+        poll_for_token(&client, &request, step, step, expires_at).await
+    }
+
+    // The scenarios share the pending sign-in, so they run one after another.
+    #[tokio::test]
+    async fn device_poll_follows_token_endpoint_errors() {
+        let pending = r#"{"error": "authorization_pending"}"#;
+        let approved =
+            r#"{"device_id": "d1", "token": "t1", "expires_at": "2030-01-01T00:00:00Z"}"#;
+
+        let response = poll(vec![
+            (400, pending),
+            (400, r#"{"error": "slow_down"}"#),
+            (503, "Service Unavailable"),
+            (200, approved),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.device_id, "d1");
+        assert_eq!(response.token, "t1");
+
+        let denied = poll(vec![(400, pending), (400, r#"{"error": "access_denied"}"#)])
+            .await
+            .unwrap_err();
+        assert_eq!(denied.code(), "not_authenticated");
+        assert_eq!(denied.to_string(), "Sign-in was denied");
+
+        let expired = poll(vec![(400, r#"{"error": "expired_token"}"#)])
+            .await
+            .unwrap_err();
+        assert_eq!(expired.code(), "not_authenticated");
+        assert!(expired.to_string().contains("expired"));
+
+        let rejected = poll(vec![(400, r#"{"error": "invalid_client"}"#)])
+            .await
+            .unwrap_err();
+        assert_eq!(rejected.code(), "rejected");
+    }
+}
@@ -1,3 +1,5 @@
+use crate::client;
+use crate::scanner::StoreIds;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
@@ -23,6 +25,17 @@ pub struct ManifestGame {
     pub files: HashMap<String, ManifestFile>,
     #[serde(default)]
     pub registry: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub steam: Option<ManifestStore>,
+    #[serde(default)]
+    pub gog: Option<ManifestStore>,
+}
+
+/// A game's entry in a store, e.g. `steam: {id: 620}`.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ManifestStore {
+    #[serde(default)]
+    pub id: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -89,8 +102,7 @@ impl LudusaviManifest {
     }
 
     async fn fetch_manifest() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let client = reqwest::Client::new();
-        let response = client.get(LUDUSAVI_MANIFEST_URL).send().await?;
+        let response = client::shared_http_client().get(LUDUSAVI_MANIFEST_URL).send().await?;
         let content = response.text().await?;
         Ok(content)
     }
@@ -117,6 +129,14 @@ impl LudusaviManifest {
         paths
     }
 
+    pub fn store_ids(&self, game_name: &str) -> StoreIds {
+        let game = self.games.get(game_name);
+        StoreIds {
+            steam: game.and_then(|g| g.steam.as_ref()).and_then(|s| s.id),
+            gog: game.and_then(|g| g.gog.as_ref()).and_then(|s| s.id),
+        }
+    }
+
     pub fn list_games(&self) -> Vec<String> {
         self.games.keys().cloned().collect()
     }
@@ -4,17 +4,43 @@
 )]
 
 mod api;
+mod auth;
+mod chunkstore;
+mod client;
+mod compression;
 mod config;
+mod crypto;
+mod device;
+mod diff;
+mod error;
+mod history;
+mod login;
 mod ludusavi;
+mod mapping;
+mod paths;
+mod profiles;
+mod progress;
+mod queue;
+mod restore;
+mod retention;
+mod safety;
 mod scanner;
+mod snapshot;
+mod storage;
+mod throttle;
 mod uploader;
+mod version_cache;
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use tauri::{CustomMenuItem, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu};
 
 pub struct AppState {
     pub config: Mutex<config::Config>,
     pub is_scanning: Mutex<bool>,
+    pub upload_queue: Mutex<queue::UploadQueue>,
+    pub is_draining: Mutex<bool>,
+    pub bandwidth_limiter: Arc<throttle::BandwidthLimiter>,
+    pub progress: progress::ProgressBoard,
 }
 
 fn main() {
@@ -32,10 +58,30 @@ fn main() {
     
     let system_tray = SystemTray::new().with_menu(tray_menu);
 
+    let config = config::Config::load().unwrap_or_default();
+    let bandwidth_limiter = Arc::new(throttle::BandwidthLimiter::new(&config));
+
     tauri::Builder::default()
         .manage(AppState {
-            config: Mutex::new(config::Config::load().unwrap_or_default()),
+            config: Mutex::new(config),
             is_scanning: Mutex::new(false),
+            upload_queue: Mutex::new(queue::UploadQueue::load_and_recover()),
+            is_draining: Mutex::new(false),
+            bandwidth_limiter,
+            progress: progress::ProgressBoard::default(),
+        })
+        .setup(|app| {
+            let handle = app.handle();
+            tauri::async_runtime::spawn(async move {
+                let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+                    queue::DRAIN_INTERVAL_SECS,
+                ));
+                loop {
+                    interval.tick().await;
+                    queue::drain(&handle, history::UploadTrigger::Background).await;
+                }
+            });
+            Ok(())
         })
         .system_tray(system_tray)
         .on_system_tray_event(|app, event| match event {
@@ -67,15 +113,44 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             api::get_config,
             api::save_config,
-            api::login,
+            api::get_machine_info,
+            api::login_with_browser,
+            api::start_device_login,
+            api::finish_device_login,
+            api::cancel_device_login,
             api::logout,
             api::get_auth_status,
             api::scan_games,
             api::get_detected_games,
             api::upload_saves,
+            api::get_upload_queue,
+            api::retry_upload_job,
+            api::remove_upload_job,
+            api::list_backup_versions,
+            api::get_version_history,
+            api::delete_backup_version,
+            api::set_storage_secret,
+            api::restore_save,
+            api::restore_save_files,
+            api::list_version_entries,
+            api::preview_restore,
+            api::diff_save_version,
+            api::prune_local_versions,
+            api::verify_local_store,
+            api::gc_local_store,
+            api::undo_restore,
+            api::list_safety_snapshots,
+            api::get_encryption_status,
+            api::enable_encryption,
+            api::disable_encryption,
+            api::rotate_encryption_key,
+            api::get_recovery_key,
+            api::import_recovery_key,
             api::get_upload_history,
             api::get_game_profiles,
             api::create_game_profile,
+            api::get_game_profile_mappings,
+            api::set_game_profile_mapping,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
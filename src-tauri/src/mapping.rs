@@ -0,0 +1,85 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Name of the mapping entry stored at the root of every save archive.
+pub const MAPPING_FILE_NAME: &str = "saveknight-mapping.json";
+
+/// Describes where each file in a save archive came from. It is embedded in
+/// the archive and also sent alongside the upload, so the server can show
+/// it without decrypting the archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupMapping {
+    pub game_name: String,
+    pub created_at: DateTime<Utc>,
+    pub os: String,
+    pub files: Vec<MappedFile>,
+    #[serde(default)]
+    pub encryption: Option<EncryptionInfo>,
+    /// Folders the files are stored under, one per matched save path.
+    /// Archives made before roots were recorded have none.
+    #[serde(default)]
+    pub roots: Vec<ArchiveRoot>,
+}
+
+/// One matched save path. Its files are stored below `<name>/` in the
+/// archive, so two save paths holding files of the same name don't collide.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveRoot {
+    pub name: String,
+    /// The folder the entry names are relative to: the matched directory,
+    /// or the folder of a matched file.
+    pub path: String,
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MappedFile {
+    pub archive_name: String,
+    pub original_path: String,
+    pub pattern: String,
+    /// Placeholder the file was found under (e.g. `<home>`) and its path
+    /// relative to it, so it can be restored where that placeholder
+    /// resolves on another machine.
+    #[serde(default)]
+    pub placeholder: Option<String>,
+    #[serde(default)]
+    pub relative_path: Option<String>,
+    /// The game's save folder relative to the placeholder. Restores never
+    /// write outside it.
+    #[serde(default)]
+    pub save_root: Option<String>,
+    /// OS layout the placeholder refers to when it differs from the
+    /// backup's `os`, e.g. "windows" for files inside a Proton prefix.
+    #[serde(default)]
+    pub placeholder_os: Option<String>,
+    pub size_bytes: u64,
+    pub sha256: String,
+    #[serde(default)]
+    pub modified: Option<DateTime<Utc>>,
+    /// False when the file kept changing while it was being backed up.
+    #[serde(default = "default_true")]
+    pub consistent: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionInfo {
+    pub algorithm: String,
+    pub key_id: String,
+}
+
+impl BackupMapping {
+    pub fn new(game_name: &str, encryption: Option<EncryptionInfo>) -> Self {
+        Self {
+            game_name: game_name.to_string(),
+            created_at: Utc::now(),
+            os: std::env::consts::OS.to_string(),
+            files: Vec::new(),
+            encryption,
+            roots: Vec::new(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
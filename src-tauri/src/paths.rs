@@ -0,0 +1,281 @@
+use crate::config::RedirectRule;
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Placeholders that stand for a directory, in the order they are tried when
+/// matching a path. More specific directories come before `<home>`, which
+/// usually contains them.
+const ROOT_PLACEHOLDERS: &[&str] = &["<localAppData>", "<appData>", "<documents>", "<home>"];
+
+/// Where the directory placeholders live below a Windows user profile,
+/// most specific first.
+const WINDOWS_PLACEHOLDER_DIRS: &[(&str, &str)] = &[
+    ("<localAppData>", "AppData/Local"),
+    ("<appData>", "AppData/Roaming"),
+    ("<documents>", "Documents"),
+    ("<documents>", "My Documents"),
+    ("<home>", ""),
+];
+
+/// Value of a Ludusavi placeholder on this machine.
+fn placeholder_value(placeholder: &str) -> Option<String> {
+    let path = match placeholder {
+        "<home>" => dirs::home_dir(),
+        "<documents>" => dirs::document_dir(),
+        "<appData>" => dirs::data_dir(),
+        "<localAppData>" => dirs::data_local_dir(),
+        "<osUserName>" => {
+            return Some(
+                std::env::var("USERNAME")
+                    .or_else(|_| std::env::var("USER"))
+                    .unwrap_or_else(|_| "user".to_string()),
+            )
+        }
+        "<storeUserId>" => return Some("*".to_string()),
+        _ => return None,
+    };
+    path.map(|p| p.to_string_lossy().to_string())
+}
+
+/// Replaces the placeholders in a manifest path with their values on this
+/// machine, giving a glob pattern.
+pub fn resolve_pattern(pattern: &str) -> String {
+    let mut resolved = pattern.to_string();
+    for placeholder in ROOT_PLACEHOLDERS
+        .iter()
+        .chain(&["<storeUserId>", "<osUserName>"])
+    {
+        if resolved.contains(placeholder) {
+            let value = placeholder_value(placeholder).unwrap_or_default();
+            resolved = resolved.replace(placeholder, &value);
+        }
+    }
+    resolved.replace('/', std::path::MAIN_SEPARATOR_STR)
+}
+
+/// A backed-up file's position relative to a directory placeholder, so it
+/// can be restored wherever that placeholder resolves on another machine.
+pub struct Location {
+    pub placeholder: String,
+    /// Path of the file below the placeholder, with `/` separators.
+    pub relative_path: String,
+    /// The game's save folder below the placeholder, if the file's pattern
+    /// lies below the same placeholder.
+    pub save_root: Option<String>,
+    /// OS whose directory layout the placeholder refers to. Files inside a
+    /// Proton or Wine prefix use the Windows layout.
+    pub os: String,
+}
+
+/// Works out the [`Location`] of a file found on this machine through
+/// `pattern`.
+pub fn locate(path: &Path, pattern: &str) -> Option<Location> {
+    let root = save_root(pattern);
+    let relative = |base: &Path, path: &Path| {
+        path.strip_prefix(base)
+            .ok()
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+    };
+
+    if let Some(user_dir) = wine_user_dir(path) {
+        return WINDOWS_PLACEHOLDER_DIRS
+            .iter()
+            .find_map(|(placeholder, dir)| {
+                let base = user_dir.join(dir);
+                Some(Location {
+                    placeholder: placeholder.to_string(),
+                    relative_path: relative(&base, path)?,
+                    save_root: relative(&base, &root),
+                    os: "windows".to_string(),
+                })
+            });
+    }
+
+    ROOT_PLACEHOLDERS.iter().find_map(|placeholder| {
+        let base = PathBuf::from(placeholder_value(placeholder)?);
+        Some(Location {
+            placeholder: placeholder.to_string(),
+            relative_path: relative(&base, path)?,
+            save_root: relative(&base, &root),
+            os: std::env::consts::OS.to_string(),
+        })
+    })
+}
+
+/// The Windows user profile inside a Proton or Wine prefix that `path` lies
+/// in, i.e. everything up to `drive_c/users/<name>`.
+fn wine_user_dir(path: &Path) -> Option<PathBuf> {
+    let components: Vec<Component> = path.components().collect();
+    let index = components
+        .windows(3)
+        .position(|w| w[0].as_os_str() == "drive_c" && w[1].as_os_str() == "users")?;
+    Some(components[..index + 3].iter().collect())
+}
+
+/// A set of directories placeholders can resolve to on this machine: the
+/// native ones, or those of a Windows profile inside a Proton prefix.
+pub struct PathEnvironment {
+    pub kind: &'static str,
+    user_dir: Option<PathBuf>,
+}
+
+impl PathEnvironment {
+    fn native() -> Self {
+        Self {
+            kind: "native",
+            user_dir: None,
+        }
+    }
+
+    pub fn placeholder_dir(&self, placeholder: &str) -> Option<PathBuf> {
+        match &self.user_dir {
+            None => placeholder_value(placeholder).map(PathBuf::from),
+            Some(user_dir) => {
+                let dirs = WINDOWS_PLACEHOLDER_DIRS
+                    .iter()
+                    .filter(|(p, _)| *p == placeholder);
+                let candidates: Vec<PathBuf> = dirs.map(|(_, dir)| user_dir.join(dir)).collect();
+                candidates
+                    .iter()
+                    .find(|dir| dir.is_dir())
+                    .or(candidates.first())
+                    .cloned()
+            }
+        }
+    }
+}
+
+/// Environments a file recorded with `source_os`'s layout could go to on
+/// this machine, best guess first. Windows saves on Linux may belong in any
+/// Proton prefix or be read by a native port; Unix saves have no automatic
+/// counterpart on Windows.
+pub fn environments_for(source_os: &str) -> Vec<PathEnvironment> {
+    let source_windows = source_os == "windows";
+    let here_windows = cfg!(windows);
+
+    if source_windows == here_windows {
+        return vec![PathEnvironment::native()];
+    }
+    if here_windows {
+        return Vec::new();
+    }
+
+    let mut environments: Vec<PathEnvironment> = proton_user_dirs()
+        .into_iter()
+        .map(|user_dir| PathEnvironment {
+            kind: "proton",
+            user_dir: Some(user_dir),
+        })
+        .collect();
+    environments.push(PathEnvironment::native());
+    environments
+}
+
+/// Windows user profiles of every Proton prefix in the Steam libraries
+/// found under the home directory.
+fn proton_user_dirs() -> Vec<PathBuf> {
+    let home = match dirs::home_dir() {
+        Some(home) => home,
+        None => return Vec::new(),
+    };
+    let library_path = Regex::new(r#""path"\s+"([^"]+)""#).expect("valid library pattern");
+
+    let mut libraries = Vec::new();
+    for steam in [
+        home.join(".steam/steam"),
+        home.join(".local/share/Steam"),
+        home.join(".var/app/com.valvesoftware.Steam/.local/share/Steam"),
+    ] {
+        if !steam.is_dir() {
+            continue;
+        }
+        if let Ok(folders) = fs::read_to_string(steam.join("steamapps/libraryfolders.vdf")) {
+            libraries.extend(
+                library_path
+                    .captures_iter(&folders)
+                    .map(|c| PathBuf::from(c[1].replace("\\\\", "\\"))),
+            );
+        }
+        libraries.push(steam);
+    }
+
+    let mut seen = HashSet::new();
+    let mut user_dirs = Vec::new();
+    for library in libraries {
+        let compatdata = library.join("steamapps/compatdata");
+        for entry in fs::read_dir(compatdata).into_iter().flatten().flatten() {
+            let user_dir = entry.path().join("pfx/drive_c/users/steamuser");
+            if !user_dir.is_dir() {
+                continue;
+            }
+            if seen.insert(user_dir.canonicalize().unwrap_or_else(|_| user_dir.clone())) {
+                user_dirs.push(user_dir);
+            }
+        }
+    }
+    user_dirs
+}
+
+/// Directory a manifest path can write into on this machine: the part of
+/// the resolved pattern before its first wildcard.
+pub fn save_root(pattern: &str) -> PathBuf {
+    let resolved = resolve_pattern(pattern);
+    let mut root = PathBuf::new();
+    for component in Path::new(&resolved).components() {
+        if component
+            .as_os_str()
+            .to_string_lossy()
+            .contains(['*', '?', '['])
+        {
+            break;
+        }
+        root.push(component);
+    }
+    root
+}
+
+/// Joins a relative path taken from an archive onto `root`, refusing
+/// anything that could escape it (`..`, absolute paths, drive prefixes).
+/// An empty relative path gives `root` itself.
+pub fn join_within(root: &Path, relative: &str) -> Option<PathBuf> {
+    let mut joined = root.to_path_buf();
+    for component in Path::new(relative).components() {
+        match component {
+            Component::Normal(part) => joined.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(joined)
+}
+
+/// Whether `path` is `root` or lies below it, without touching the
+/// filesystem. Paths containing `..` are never inside.
+pub fn is_within(root: &Path, path: &Path) -> bool {
+    !path.components().any(|c| c == Component::ParentDir) && path.starts_with(root)
+}
+
+/// Applies the first matching redirect rule to a path from another machine.
+/// Prefixes are compared without regard to case or separator style, since
+/// the path may come from Windows. Returns the rule's target and the
+/// redirected path.
+pub fn redirect(original: &str, rules: &[RedirectRule]) -> Option<(PathBuf, PathBuf)> {
+    let original = original.replace('\\', "/");
+    rules.iter().find_map(|rule| {
+        let source = rule.source.replace('\\', "/");
+        let source = source.trim_end_matches('/');
+        let head = original.get(..source.len())?;
+        let rest = &original[source.len()..];
+        if source.is_empty() || !head.eq_ignore_ascii_case(source) {
+            return None;
+        }
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        let target = PathBuf::from(&rule.target);
+        let redirected = join_within(&target, rest.trim_start_matches('/'))?;
+        Some((target, redirected))
+    })
+}
@@ -0,0 +1,82 @@
+use crate::client::{ApiClient, ApiError, GameProfile, NewGameProfile};
+use crate::scanner::StoreIds;
+
+/// Platform of the profiles created for detected games.
+const DEFAULT_PLATFORM: &str = "PC";
+
+/// Finds or creates the SaveKnight game profile of detected games. The
+/// server's profiles are fetched once and reused for every game after.
+pub struct ProfileResolver<'a> {
+    client: &'a ApiClient,
+    profiles: Option<Vec<GameProfile>>,
+}
+
+impl<'a> ProfileResolver<'a> {
+    pub fn new(client: &'a ApiClient) -> Self {
+        Self {
+            client,
+            profiles: None,
+        }
+    }
+
+    async fn profiles(&mut self) -> Result<&mut Vec<GameProfile>, ApiError> {
+        if self.profiles.is_none() {
+            self.profiles = Some(self.client.game_profiles().await?);
+        }
+        Ok(self.profiles.get_or_insert_with(Vec::new))
+    }
+
+    /// Whether a profile with this ID exists.
+    pub async fn exists(&mut self, profile_id: &str) -> Result<bool, ApiError> {
+        Ok(self.profiles().await?.iter().any(|p| p.id == profile_id))
+    }
+
+    /// The profile for a game: one with the same store ID, else one with the
+    /// same name, else a newly created one.
+    pub async fn resolve(
+        &mut self,
+        game_name: &str,
+        store_ids: &StoreIds,
+    ) -> Result<GameProfile, ApiError> {
+        if let Some(profile) = find_match(self.profiles().await?, game_name, store_ids) {
+            return Ok(profile.clone());
+        }
+
+        let profile = self
+            .client
+            .create_game_profile(&NewGameProfile {
+                name: game_name,
+                platform: DEFAULT_PLATFORM,
+                store_ids: store_ids.clone(),
+            })
+            .await?;
+        log::info!("Created game profile {} for {}", profile.id, game_name);
+        self.profiles().await?.push(profile.clone());
+        Ok(profile)
+    }
+}
+
+fn find_match<'p>(
+    profiles: &'p [GameProfile],
+    game_name: &str,
+    store_ids: &StoreIds,
+) -> Option<&'p GameProfile> {
+    let name = normalize_name(game_name);
+    profiles
+        .iter()
+        .find(|p| p.store_ids.matches(store_ids))
+        .or_else(|| {
+            profiles
+                .iter()
+                .find(|p| normalize_name(&p.name) == name && !p.store_ids.conflicts(store_ids))
+        })
+}
+
+/// Lowercase letters and digits only, so "Hollow Knight" matches
+/// "hollow-knight" and "DOOM: Eternal" matches "Doom Eternal".
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
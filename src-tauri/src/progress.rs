@@ -0,0 +1,204 @@
+use crate::AppState;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+pub const PROGRESS_EVENT: &str = "upload-progress";
+const EMIT_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressStage {
+    Archiving,
+    Uploading,
+    Completed,
+    Queued,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadProgress {
+    pub job_id: String,
+    pub game_name: String,
+    pub stage: ProgressStage,
+    pub files_done: u32,
+    pub files_total: u32,
+    pub bytes_sent: u64,
+    pub bytes_total: u64,
+    pub bytes_per_sec: u64,
+    pub eta_secs: Option<u64>,
+    pub message: Option<String>,
+}
+
+/// Latest progress of every job that is still archiving or uploading, used
+/// to build the tray tooltip.
+#[derive(Default)]
+pub struct ProgressBoard {
+    active: Mutex<HashMap<String, UploadProgress>>,
+}
+
+impl ProgressBoard {
+    fn record(&self, progress: &UploadProgress) -> String {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        match progress.stage {
+            ProgressStage::Archiving | ProgressStage::Uploading => {
+                active.insert(progress.job_id.clone(), progress.clone());
+            }
+            _ => {
+                active.remove(&progress.job_id);
+            }
+        }
+        tray_tooltip(active.values())
+    }
+}
+
+fn tray_tooltip<'a>(active: impl Iterator<Item = &'a UploadProgress>) -> String {
+    let mut games = 0;
+    let mut sent = 0;
+    let mut total = 0;
+    let mut rate = 0;
+    for progress in active {
+        games += 1;
+        sent += progress.bytes_sent;
+        total += progress.bytes_total;
+        rate += progress.bytes_per_sec;
+    }
+
+    if games == 0 {
+        return "SaveKnight".to_string();
+    }
+    if total == 0 {
+        return format!("SaveKnight - preparing {} backup(s)", games);
+    }
+    format!(
+        "SaveKnight - backing up {} game(s), {}% ({} KB/s)",
+        games,
+        sent * 100 / total,
+        rate / 1024
+    )
+}
+
+struct Tracker {
+    upload_started: Option<Instant>,
+    last_emit: Option<Instant>,
+    files_done: u32,
+    files_total: u32,
+    bytes_sent: u64,
+    bytes_total: u64,
+}
+
+/// Emits `upload-progress` events for one job. Cheap to clone so it can be
+/// moved into the upload body stream.
+#[derive(Clone)]
+pub struct ProgressReporter {
+    app: AppHandle,
+    job_id: String,
+    game_name: String,
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+impl ProgressReporter {
+    pub fn new(app: &AppHandle, job_id: &str, game_name: &str) -> Self {
+        Self {
+            app: app.clone(),
+            job_id: job_id.to_string(),
+            game_name: game_name.to_string(),
+            tracker: Arc::new(Mutex::new(Tracker {
+                upload_started: None,
+                last_emit: None,
+                files_done: 0,
+                files_total: 0,
+                bytes_sent: 0,
+                bytes_total: 0,
+            })),
+        }
+    }
+
+    pub fn archiving(&self, files_done: u32, files_total: u32) {
+        let mut tracker = self.tracker.lock().unwrap_or_else(|e| e.into_inner());
+        tracker.files_done = files_done;
+        tracker.files_total = files_total;
+        if files_done == files_total || Self::due(&tracker) {
+            self.emit(&mut tracker, ProgressStage::Archiving, None);
+        }
+    }
+
+    pub fn upload_started(&self, bytes_total: u64) {
+        let mut tracker = self.tracker.lock().unwrap_or_else(|e| e.into_inner());
+        tracker.upload_started = Some(Instant::now());
+        tracker.bytes_sent = 0;
+        tracker.bytes_total = bytes_total;
+        self.emit(&mut tracker, ProgressStage::Uploading, None);
+    }
+
+    pub fn bytes_sent(&self, bytes: u64) {
+        let mut tracker = self.tracker.lock().unwrap_or_else(|e| e.into_inner());
+        tracker.bytes_sent += bytes;
+        if tracker.bytes_sent >= tracker.bytes_total || Self::due(&tracker) {
+            self.emit(&mut tracker, ProgressStage::Uploading, None);
+        }
+    }
+
+    /// Bytes reported as sent so far.
+    pub fn sent(&self) -> u64 {
+        self.tracker.lock().unwrap_or_else(|e| e.into_inner()).bytes_sent
+    }
+
+    /// Goes back to `bytes_sent`, for data that is about to be sent again.
+    pub fn rewind(&self, bytes_sent: u64) {
+        let mut tracker = self.tracker.lock().unwrap_or_else(|e| e.into_inner());
+        tracker.bytes_sent = tracker.bytes_sent.min(bytes_sent);
+    }
+
+    pub fn finished(&self, stage: ProgressStage, message: &str) {
+        let mut tracker = self.tracker.lock().unwrap_or_else(|e| e.into_inner());
+        self.emit(&mut tracker, stage, Some(message.to_string()));
+    }
+
+    fn due(tracker: &Tracker) -> bool {
+        match tracker.last_emit {
+            Some(last) => last.elapsed() >= EMIT_INTERVAL,
+            None => true,
+        }
+    }
+
+    fn emit(&self, tracker: &mut Tracker, stage: ProgressStage, message: Option<String>) {
+        tracker.last_emit = Some(Instant::now());
+
+        let elapsed = tracker
+            .upload_started
+            .map(|started| started.elapsed().as_secs_f64())
+            .unwrap_or_default();
+        let bytes_per_sec = if elapsed > 0.0 {
+            (tracker.bytes_sent as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let eta_secs = if stage == ProgressStage::Uploading && bytes_per_sec > 0 {
+            Some(tracker.bytes_total.saturating_sub(tracker.bytes_sent) / bytes_per_sec)
+        } else {
+            None
+        };
+
+        let progress = UploadProgress {
+            job_id: self.job_id.clone(),
+            game_name: self.game_name.clone(),
+            stage,
+            files_done: tracker.files_done,
+            files_total: tracker.files_total,
+            bytes_sent: tracker.bytes_sent,
+            bytes_total: tracker.bytes_total,
+            bytes_per_sec,
+            eta_secs,
+            message,
+        };
+
+        let tooltip = self.app.state::<AppState>().progress.record(&progress);
+        self.app.tray_handle().set_tooltip(&tooltip).ok();
+        if let Err(e) = self.app.emit_all(PROGRESS_EVENT, progress) {
+            log::warn!("Failed to emit progress event: {}", e);
+        }
+    }
+}
@@ -0,0 +1,551 @@
+use crate::auth;
+use crate::client::ApiClient;
+use crate::config::{Config, StorageTarget};
+use crate::error::AppError;
+use crate::history::{self, AttemptResult, HistoryEntry, UploadTrigger};
+use crate::mapping::BackupMapping;
+use crate::profiles::ProfileResolver;
+use crate::progress::{ProgressReporter, ProgressStage};
+use crate::retention;
+use crate::scanner::StoreIds;
+use crate::storage::{self, GameRef, StorageBackend, StorageError};
+use crate::uploader::UploadResult;
+use crate::AppState;
+use chrono::{DateTime, Duration, Utc};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tauri::{AppHandle, Manager};
+
+pub const DRAIN_INTERVAL_SECS: u64 = 30;
+const MAX_BACKOFF_MINUTES: i64 = 60;
+/// Transient failures in a row after which a drain stops starting jobs for
+/// a target.
+const TRANSIENT_FAILURES_BEFORE_OFFLINE: u32 = 3;
+
+static JOB_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Uploading,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadJob {
+    pub id: String,
+    pub game_name: String,
+    pub game_profile_id: String,
+    pub archive_path: String,
+    pub local_path: String,
+    pub checksum: String,
+    pub size_bytes: u64,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub next_attempt_at: DateTime<Utc>,
+    #[serde(default)]
+    pub key_id: Option<String>,
+    #[serde(default)]
+    pub mapping: Option<BackupMapping>,
+    /// ID of the storage target this job uploads to. Jobs for the same
+    /// snapshot share one staged archive.
+    #[serde(default = "default_target")]
+    pub target: String,
+    /// Used to find or create the game profile when `game_profile_id` is
+    /// empty because the game had none yet.
+    #[serde(default)]
+    pub store_ids: StoreIds,
+}
+
+fn default_target() -> String {
+    StorageTarget::SaveKnight.id()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadQueue {
+    jobs: Vec<UploadJob>,
+}
+
+impl UploadQueue {
+    pub fn queue_path() -> PathBuf {
+        let mut path = Config::data_dir();
+        path.push("upload-queue.json");
+        path
+    }
+
+    pub fn staging_dir() -> PathBuf {
+        let mut path = Config::data_dir();
+        path.push("staging");
+        fs::create_dir_all(&path).ok();
+        path
+    }
+
+    pub fn new_job_id() -> String {
+        format!(
+            "{}-{:04x}",
+            Utc::now().format("%Y%m%d%H%M%S%6f"),
+            JOB_COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff
+        )
+    }
+
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Self::queue_path();
+        if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            let queue: UploadQueue = serde_json::from_str(&content)?;
+            Ok(queue)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Loads the queue at startup. Jobs that were mid-upload when the app
+    /// exited go back to pending, and staged archives that no job refers to
+    /// any more are deleted.
+    pub fn load_and_recover() -> Self {
+        let mut queue = Self::load().unwrap_or_else(|e| {
+            log::warn!("Failed to load upload queue: {}", e);
+            Self::default()
+        });
+
+        let now = Utc::now();
+        for job in &mut queue.jobs {
+            if job.status == JobStatus::Uploading {
+                job.status = JobStatus::Pending;
+                job.next_attempt_at = now;
+            }
+        }
+
+        queue.cleanup_stale_archives();
+        queue.save().ok();
+        queue
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Self::queue_path();
+        let tmp_path = path.with_extension("json.tmp");
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(&tmp_path, content)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn jobs(&self) -> &[UploadJob] {
+        &self.jobs
+    }
+
+    pub fn enqueue(&mut self, job: UploadJob) {
+        self.jobs.push(job);
+    }
+
+    /// Removes a job, and its staged archive once no other job needs it.
+    pub fn remove(&mut self, job_id: &str) -> Option<UploadJob> {
+        let index = self.jobs.iter().position(|j| j.id == job_id)?;
+        let job = self.jobs.remove(index);
+        if !self.jobs.iter().any(|j| j.archive_path == job.archive_path) {
+            fs::remove_file(&job.archive_path).ok();
+        }
+        Some(job)
+    }
+
+    /// Makes a job (or every job when `job_id` is `None`) due immediately,
+    /// including jobs that were marked as failed.
+    pub fn retry(&mut self, job_id: Option<&str>) -> usize {
+        let now = Utc::now();
+        let mut count = 0;
+        for job in &mut self.jobs {
+            if (job_id.is_none() || job_id == Some(job.id.as_str()))
+                && job.status != JobStatus::Uploading
+            {
+                job.status = JobStatus::Pending;
+                job.next_attempt_at = now;
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Points the game's SaveKnight jobs that haven't started at another
+    /// game profile, or back to automatic matching with `None`.
+    pub fn set_game_profile(&mut self, game_name: &str, profile_id: Option<&str>) {
+        let saveknight = StorageTarget::SaveKnight.id();
+        for job in &mut self.jobs {
+            if job.game_name == game_name
+                && job.target == saveknight
+                && job.status != JobStatus::Uploading
+            {
+                job.game_profile_id = profile_id.unwrap_or_default().to_string();
+            }
+        }
+    }
+
+    /// Claims the next due job whose target is one of `targets`.
+    fn take_next_due(&mut self, now: DateTime<Utc>, targets: &[String]) -> Option<UploadJob> {
+        let job = self
+            .jobs
+            .iter_mut()
+            .filter(|j| j.status == JobStatus::Pending && j.next_attempt_at <= now)
+            .filter(|j| targets.contains(&j.target))
+            .min_by_key(|j| j.next_attempt_at)?;
+        job.status = JobStatus::Uploading;
+        job.attempts += 1;
+        Some(job.clone())
+    }
+
+    /// The waiting jobs whose target isn't one of `targets`.
+    fn without_target(&self, targets: &[String]) -> Vec<UploadJob> {
+        self.jobs
+            .iter()
+            .filter(|j| j.status == JobStatus::Pending && !targets.contains(&j.target))
+            .cloned()
+            .collect()
+    }
+
+    fn mark_failed(&mut self, job_id: &str, error: String) {
+        if let Some(job) = self.jobs.iter_mut().find(|j| j.id == job_id) {
+            job.status = JobStatus::Failed;
+            job.last_error = Some(error);
+        }
+    }
+
+    fn mark_retry_later(&mut self, job_id: &str, error: String) {
+        if let Some(job) = self.jobs.iter_mut().find(|j| j.id == job_id) {
+            let exponent = job.attempts.saturating_sub(1).min(6);
+            let backoff = (1i64 << exponent).min(MAX_BACKOFF_MINUTES);
+            job.status = JobStatus::Pending;
+            job.last_error = Some(error);
+            job.next_attempt_at = Utc::now() + Duration::minutes(backoff);
+        }
+    }
+
+    fn cleanup_stale_archives(&self) {
+        let entries = match fs::read_dir(Self::staging_dir()) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let path_str = path.to_string_lossy();
+            let referenced = self.jobs.iter().any(|j| j.archive_path == path_str);
+            if !referenced {
+                log::info!("Removing stale staged archive {}", path.display());
+                if path.is_dir() {
+                    fs::remove_dir_all(&path).ok();
+                } else {
+                    fs::remove_file(&path).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Uploads every due job in the queue, up to `upload_concurrency` at a time.
+/// A transient failure only defers its own job, but after
+/// `TRANSIENT_FAILURES_BEFORE_OFFLINE` in a row no more jobs are started for
+/// that target, since it is then most likely offline; its remaining jobs are
+/// picked up by the next drain.
+pub async fn drain(app: &AppHandle, trigger: UploadTrigger) -> Vec<UploadResult> {
+    let state = app.state::<AppState>();
+    {
+        let mut is_draining = state.is_draining.lock().unwrap_or_else(|e| e.into_inner());
+        if *is_draining {
+            return Vec::new();
+        }
+        *is_draining = true;
+    }
+
+    let results = drain_due_jobs(app, &state, trigger).await;
+
+    *state.is_draining.lock().unwrap_or_else(|e| e.into_inner()) = false;
+    results
+}
+
+async fn drain_due_jobs(
+    app: &AppHandle,
+    state: &AppState,
+    trigger: UploadTrigger,
+) -> Vec<UploadResult> {
+    let mut results = Vec::new();
+
+    let config = state
+        .config
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    let concurrency = config.upload_concurrency.max(1) as usize;
+    let client = ApiClient::new(&config);
+    let token = auth::valid_token(&client).await;
+    // Shared by the SaveKnight jobs, which resolve their profiles one at a
+    // time so a game doesn't get two; other targets don't wait for it.
+    let resolver = tokio::sync::Mutex::new(ProfileResolver::new(&client));
+    let saveknight = StorageTarget::SaveKnight.id();
+    let backends: HashMap<String, Arc<dyn StorageBackend>> = storage::backends_from_config(
+        &config,
+        token.as_deref(),
+        Some(state.bandwidth_limiter.clone()),
+    )
+    .into_iter()
+    .map(|backend| (backend.id(), backend))
+    .collect();
+
+    let mut online_targets: Vec<String> = backends.keys().cloned().collect();
+    results.extend(fail_jobs_without_backend(
+        app,
+        state,
+        &config,
+        &online_targets,
+        trigger,
+    ));
+    let mut transient_failures: HashMap<String, u32> = HashMap::new();
+    let local_targets: Vec<String> = config
+        .storage_targets
+        .iter()
+        .filter(|target| matches!(target, StorageTarget::Local { .. }))
+        .map(StorageTarget::id)
+        .collect();
+    let mut in_flight = FuturesUnordered::new();
+
+    loop {
+        while in_flight.len() < concurrency {
+            let job = {
+                let mut queue = state.upload_queue.lock().unwrap_or_else(|e| e.into_inner());
+                let job = queue.take_next_due(Utc::now(), &online_targets);
+                queue.save().ok();
+                job
+            };
+            let mut job = match job {
+                Some(job) => job,
+                None => break,
+            };
+            let backend = backends[&job.target].clone();
+            let progress = ProgressReporter::new(app, &job.id, &job.game_name);
+            let needs_profile = job.target == saveknight && job.game_profile_id.is_empty();
+            let resolver = &resolver;
+            in_flight.push(async move {
+                let assigned = if needs_profile {
+                    assign_game_profile(state, &mut *resolver.lock().await, &mut job).await
+                } else {
+                    Ok(())
+                };
+                let outcome = match assigned {
+                    Ok(()) => backend.put_version(&job, &progress).await,
+                    Err(e) => Err(e),
+                };
+                (job, progress, outcome)
+            });
+        }
+
+        let (job, progress, outcome) = match in_flight.next().await {
+            Some(finished) => finished,
+            None => break,
+        };
+
+        let mut prune = false;
+        {
+            let mut queue = state.upload_queue.lock().unwrap_or_else(|e| e.into_inner());
+            match outcome {
+                Ok(version) => {
+                    history::record(
+                        &HistoryEntry::for_job(&job, trigger, AttemptResult::Succeeded)
+                            .with_version(&version),
+                    );
+                    queue.remove(&job.id);
+                    queue.save().ok();
+                    transient_failures.remove(&job.target);
+                    prune = local_targets.contains(&job.target);
+
+                    let mut message =
+                        format!("Uploaded {} bytes to {}", job.size_bytes, job.target);
+                    let inconsistent = job
+                        .mapping
+                        .as_ref()
+                        .map_or(0, |m| m.files.iter().filter(|f| !f.consistent).count());
+                    if inconsistent > 0 {
+                        message.push_str(&format!(
+                            " ({} file(s) changed during the backup and may be incomplete)",
+                            inconsistent
+                        ));
+                    }
+                    progress.finished(ProgressStage::Completed, &message);
+                    results.push(UploadResult {
+                        game_name: job.game_name.clone(),
+                        success: true,
+                        message,
+                        code: None,
+                        upload_id: Some(version.id),
+                        version_number: version.version_number,
+                        job_id: Some(job.id.clone()),
+                        target: Some(job.target.clone()),
+                        queued: false,
+                    });
+                }
+                Err(e) if !e.is_transient() => {
+                    let e = AppError::from(e);
+                    history::record(
+                        &HistoryEntry::for_job(&job, trigger, AttemptResult::Failed).with_error(&e),
+                    );
+                    queue.mark_failed(&job.id, e.to_string());
+                    queue.save().ok();
+                    progress.finished(ProgressStage::Failed, &e.to_string());
+                    results.push(UploadResult {
+                        game_name: job.game_name.clone(),
+                        success: false,
+                        message: e.to_string(),
+                        code: Some(e.code().to_string()),
+                        upload_id: None,
+                        version_number: None,
+                        job_id: Some(job.id.clone()),
+                        target: Some(job.target.clone()),
+                        queued: false,
+                    });
+                }
+                Err(e) => {
+                    let e = AppError::from(e);
+                    log::warn!(
+                        "Upload of {} to {} deferred: {}",
+                        job.game_name,
+                        job.target,
+                        e
+                    );
+                    history::record(
+                        &HistoryEntry::for_job(&job, trigger, AttemptResult::Deferred)
+                            .with_error(&e),
+                    );
+                    queue.mark_retry_later(&job.id, e.to_string());
+                    queue.save().ok();
+                    progress.finished(ProgressStage::Queued, &e.to_string());
+                    results.push(UploadResult {
+                        game_name: job.game_name.clone(),
+                        success: false,
+                        message: format!("Queued for retry: {}", e),
+                        code: Some(e.code().to_string()),
+                        upload_id: None,
+                        version_number: None,
+                        job_id: Some(job.id.clone()),
+                        target: Some(job.target.clone()),
+                        queued: true,
+                    });
+                    let failures = transient_failures.entry(job.target.clone()).or_default();
+                    *failures += 1;
+                    if *failures >= TRANSIENT_FAILURES_BEFORE_OFFLINE {
+                        online_targets.retain(|t| *t != job.target);
+                    }
+                }
+            }
+        }
+
+        if prune {
+            let game = GameRef {
+                name: job.game_name.clone(),
+                profile_id: None,
+            };
+            let policy = config.retention_for(&job.game_name);
+            if let Err(e) = retention::apply(backends[&job.target].as_ref(), &game, &policy).await {
+                log::warn!("Failed to prune {} on {}: {}", job.game_name, job.target, e);
+            }
+        }
+    }
+
+    results
+}
+
+/// Fails the jobs for a target that was removed or can't be used, e.g.
+/// for want of a stored secret, which would otherwise wait in the queue
+/// forever without saying why. They can be retried once it is fixed.
+fn fail_jobs_without_backend(
+    app: &AppHandle,
+    state: &AppState,
+    config: &Config,
+    targets: &[String],
+    trigger: UploadTrigger,
+) -> Vec<UploadResult> {
+    let mut queue = state.upload_queue.lock().unwrap_or_else(|e| e.into_inner());
+    let jobs = queue.without_target(targets);
+    let mut results = Vec::new();
+    for job in jobs {
+        let e = missing_backend_error(config, &job.target);
+        history::record(
+            &HistoryEntry::for_job(&job, trigger, AttemptResult::Failed).with_error(&e),
+        );
+        queue.mark_failed(&job.id, e.to_string());
+        ProgressReporter::new(app, &job.id, &job.game_name)
+            .finished(ProgressStage::Failed, &e.to_string());
+        results.push(UploadResult {
+            game_name: job.game_name.clone(),
+            success: false,
+            message: e.to_string(),
+            code: Some(e.code().to_string()),
+            upload_id: None,
+            version_number: None,
+            job_id: Some(job.id.clone()),
+            target: Some(job.target.clone()),
+            queued: false,
+        });
+    }
+    if !results.is_empty() {
+        queue.save().ok();
+    }
+    results
+}
+
+fn missing_backend_error(config: &Config, target: &str) -> AppError {
+    if target == StorageTarget::SaveKnight.id() {
+        AppError::NotAuthenticated("Sign in to SaveKnight to upload this backup".to_string())
+    } else if config.storage_targets.iter().any(|t| t.id() == target) {
+        AppError::InvalidInput(format!(
+            "The storage target {} can't be used; check its settings and credentials",
+            target
+        ))
+    } else {
+        AppError::InvalidInput(format!(
+            "The storage target {} is no longer configured",
+            target
+        ))
+    }
+}
+
+/// Fills in the game profile of a SaveKnight job queued before its game had
+/// one: the mapping in the config if one has been set since, else a matching
+/// or new profile, which is then remembered for the game.
+async fn assign_game_profile(
+    state: &AppState,
+    resolver: &mut ProfileResolver<'_>,
+    job: &mut UploadJob,
+) -> Result<(), StorageError> {
+    let mapped = state
+        .config
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .game_profiles
+        .get(&job.game_name)
+        .cloned();
+    let profile_id = match mapped {
+        Some(profile_id) => profile_id,
+        None => {
+            let profile = resolver.resolve(&job.game_name, &job.store_ids).await?;
+            let mut config = state.config.lock().unwrap_or_else(|e| e.into_inner());
+            config
+                .game_profiles
+                .insert(job.game_name.clone(), profile.id.clone());
+            if let Err(e) = config.save() {
+                log::warn!("Failed to save the game profile of {}: {}", job.game_name, e);
+            }
+            profile.id
+        }
+    };
+
+    let mut queue = state.upload_queue.lock().unwrap_or_else(|e| e.into_inner());
+    queue.set_game_profile(&job.game_name, Some(&profile_id));
+    queue.save().ok();
+    job.game_profile_id = profile_id;
+    Ok(())
+}
@@ -0,0 +1,578 @@
+use crate::config::{Config, RedirectRule};
+use crate::crypto;
+use crate::error::AppError;
+use crate::mapping::{BackupMapping, MappedFile, MAPPING_FILE_NAME};
+use crate::paths::{self, PathEnvironment};
+use crate::queue::UploadQueue;
+use crate::safety::SafetySnapshot;
+use crate::snapshot::StagingArea;
+use crate::storage::{GameRef, StorageBackend, StoredVersion};
+use crate::uploader::calculate_checksum;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use zip::ZipArchive;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoredFile {
+    pub archive_name: String,
+    /// Where the file was (or would have been) written on this machine.
+    pub path: Option<String>,
+    pub success: bool,
+    pub error: Option<String>,
+    /// [`AppError`] code of the failure, e.g. "permission_denied".
+    #[serde(default)]
+    pub error_code: Option<String>,
+}
+
+/// Where one file of a backup would be restored to on this machine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub archive_name: String,
+    pub original_path: String,
+    pub destination: Option<String>,
+    /// How the destination was found: "redirect", "native", "proton" or
+    /// "original".
+    pub resolved_by: Option<String>,
+    pub error: Option<String>,
+    /// Save folder the destination must stay inside.
+    #[serde(skip)]
+    root: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RestoreReport {
+    pub game_name: String,
+    pub version_id: String,
+    pub target: String,
+    pub files: Vec<RestoredFile>,
+    pub restored: u32,
+    pub failed: u32,
+    /// Snapshot of the files as they were before, for `undo_restore`.
+    pub safety_snapshot_id: Option<String>,
+}
+
+/// One file inside a backup version's archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub archive_name: String,
+    pub original_path: Option<String>,
+    pub size_bytes: u64,
+    /// Only known when the archive itself was read.
+    pub compressed_bytes: Option<u64>,
+    pub modified: Option<DateTime<Utc>>,
+    /// Where the file would be restored to, as in [`plan_restore`].
+    pub destination: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Lists the files in a version. Archives that can be read in place only
+/// have their zip central directory and mapping read; otherwise the mapping
+/// stored with the version is used, and the archive is downloaded only if
+/// there is none.
+pub async fn list_entries(
+    backend: &dyn StorageBackend,
+    game: &GameRef,
+    version_id: &str,
+    config: &Config,
+) -> Result<Vec<ArchiveEntry>, BoxError> {
+    if let Some(path) = backend.archive_path(game, version_id) {
+        if crypto::archive_key_id(&path)?.is_none() {
+            return read_entries(&path, config);
+        }
+    }
+
+    let version = backend
+        .list_versions(game)
+        .await?
+        .into_iter()
+        .find(|v| v.id == version_id)
+        .ok_or_else(|| format!("Version {} not found", version_id))?;
+    if let Some(mapping) = &version.mapping {
+        let plan = plan_restore(mapping, &config.restore_redirects);
+        return Ok(mapping
+            .files
+            .iter()
+            .zip(plan)
+            .map(|(file, planned)| ArchiveEntry {
+                archive_name: file.archive_name.clone(),
+                original_path: Some(file.original_path.clone()),
+                size_bytes: file.size_bytes,
+                compressed_bytes: None,
+                modified: file.modified,
+                destination: planned.destination,
+                error: planned.error,
+            })
+            .collect());
+    }
+
+    let work_dir = restore_work_dir()?;
+    let (_, archive_path) = fetch_archive(backend, game, version_id, &work_dir, None).await?;
+    read_entries(&archive_path, config)
+}
+
+/// The file mapping of a version: from its stored metadata when present,
+/// otherwise from the archive, read in place or downloaded.
+pub async fn version_mapping(
+    backend: &dyn StorageBackend,
+    game: &GameRef,
+    version_id: &str,
+) -> Result<BackupMapping, BoxError> {
+    let version = backend
+        .list_versions(game)
+        .await?
+        .into_iter()
+        .find(|v| v.id == version_id)
+        .ok_or_else(|| format!("Version {} not found", version_id))?;
+    if let Some(mapping) = version.mapping {
+        return Ok(mapping);
+    }
+
+    let read_mapping = |path: &Path| -> Result<BackupMapping, BoxError> {
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+        let entry = zip
+            .by_name(MAPPING_FILE_NAME)
+            .map_err(|_| no_mapping("Archive has no file mapping"))?;
+        Ok(serde_json::from_reader(entry)?)
+    };
+    if let Some(path) = backend.archive_path(game, version_id) {
+        if crypto::archive_key_id(&path)?.is_none() {
+            return read_mapping(&path);
+        }
+    }
+    let work_dir = restore_work_dir()?;
+    let (_, archive_path) = fetch_archive(backend, game, version_id, &work_dir, None).await?;
+    read_mapping(&archive_path)
+}
+
+/// Reads the entries of a local, unencrypted archive. Opening a zip only
+/// parses its central directory; of the contents just the mapping is read.
+fn read_entries(archive_path: &Path, config: &Config) -> Result<Vec<ArchiveEntry>, BoxError> {
+    let mut zip = ZipArchive::new(File::open(archive_path)?)?;
+    let mapping: Option<BackupMapping> = match zip.by_name(MAPPING_FILE_NAME) {
+        Ok(entry) => Some(serde_json::from_reader(entry)?),
+        Err(_) => None,
+    };
+    let plan = mapping
+        .as_ref()
+        .map(|mapping| plan_restore(mapping, &config.restore_redirects))
+        .unwrap_or_default();
+
+    let mut entries = Vec::with_capacity(zip.len());
+    for index in 0..zip.len() {
+        let entry = zip.by_index_raw(index)?;
+        if entry.is_dir() || entry.name() == MAPPING_FILE_NAME {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let planned = plan.iter().find(|p| p.archive_name == name);
+        let file = mapping
+            .as_ref()
+            .and_then(|m| m.files.iter().find(|f| f.archive_name == name));
+        entries.push(ArchiveEntry {
+            original_path: file.map(|f| f.original_path.clone()),
+            size_bytes: entry.size(),
+            compressed_bytes: Some(entry.compressed_size()),
+            modified: file.and_then(|f| f.modified),
+            destination: planned.and_then(|p| p.destination.clone()),
+            error: match planned {
+                Some(planned) => planned.error.clone(),
+                None => Some("Not in the backup's file mapping; can't be restored".to_string()),
+            },
+            archive_name: name,
+        });
+    }
+    Ok(entries)
+}
+
+fn restore_work_dir() -> std::io::Result<StagingArea> {
+    StagingArea::create(
+        Config::data_dir()
+            .join("restore")
+            .join(UploadQueue::new_job_id()),
+    )
+}
+
+/// Downloads a version into `work_dir`, checks it against its recorded
+/// checksum and decrypts it if needed, with `passphrase` re-deriving a key
+/// that isn't on this device. Returns the path of the plain zip.
+async fn fetch_archive(
+    backend: &dyn StorageBackend,
+    game: &GameRef,
+    version_id: &str,
+    work_dir: &StagingArea,
+    passphrase: Option<&str>,
+) -> Result<(StoredVersion, PathBuf), BoxError> {
+    let download_path = work_dir.path().join("download");
+    let version = backend
+        .get_version(game, version_id, &download_path)
+        .await?;
+
+    match &version.checksum {
+        Some(expected) => {
+            let actual = calculate_checksum(&download_path)?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(AppError::Io {
+                    path: None,
+                    message: format!(
+                        "Checksum mismatch: expected {}, downloaded archive has {}",
+                        expected, actual
+                    ),
+                }
+                .into());
+            }
+        }
+        None => log::warn!("Version {} has no checksum to verify", version_id),
+    }
+
+    let archive_path = match crypto::archive_key_id(&download_path)? {
+        Some(_) => {
+            let encrypted_path = download_path.clone();
+            let passphrase = passphrase.map(str::to_string);
+            // Re-deriving a passphrase key runs Argon2.
+            let key = tokio::task::spawn_blocking(move || {
+                crypto::key_for_archive(&encrypted_path, passphrase.as_deref())
+            })
+            .await??;
+            let decrypted_path = work_dir.path().join("archive.zip");
+            crypto::decrypt_file(&download_path, &decrypted_path, &key)?;
+            decrypted_path
+        }
+        None => download_path,
+    };
+    Ok((version, archive_path))
+}
+
+/// Downloads a version, checks it against its recorded checksum, decrypts it
+/// if needed and writes each file back to where it belongs on this machine.
+/// With `only`, just the named archive entries are restored. Failing files
+/// are reported individually rather than aborting the restore. The files
+/// about to be overwritten are first copied into a safety snapshot, of which
+/// the newest `safety_snapshots_to_keep` per game are kept. `passphrase`
+/// is needed when the version's passphrase key isn't on this device.
+pub async fn restore_version(
+    backend: &dyn StorageBackend,
+    game: &GameRef,
+    version_id: &str,
+    only: Option<&[String]>,
+    passphrase: Option<&str>,
+    config: &Config,
+) -> Result<RestoreReport, BoxError> {
+    let work_dir = restore_work_dir()?;
+    let (version, archive_path) =
+        fetch_archive(backend, game, version_id, &work_dir, passphrase).await?;
+
+    let mut zip = ZipArchive::new(File::open(&archive_path)?)?;
+    let mut mapping: BackupMapping = {
+        let entry = zip
+            .by_name(MAPPING_FILE_NAME)
+            .map_err(|_| no_mapping("Archive has no file mapping and can't be restored"))?;
+        serde_json::from_reader(entry)?
+    };
+    if let Some(only) = only {
+        if let Some(missing) = only
+            .iter()
+            .find(|name| !mapping.files.iter().any(|f| &f.archive_name == *name))
+        {
+            return Err(AppError::NotFound(format!(
+                "{} is not part of version {}",
+                missing, version_id
+            ))
+            .into());
+        }
+        mapping.files.retain(|f| only.contains(&f.archive_name));
+    }
+    let plan = plan_restore(&mapping, &config.restore_redirects);
+
+    let to_snapshot: Vec<PathBuf> = plan
+        .iter()
+        .filter_map(|planned| planned.destination.as_ref().map(PathBuf::from))
+        .collect();
+    let snapshot =
+        SafetySnapshot::take(&mapping.game_name, &version.id, &to_snapshot).map_err(|e| {
+            AppError::Io {
+                path: None,
+                message: format!(
+                    "Couldn't take a safety snapshot, nothing was restored: {}",
+                    e
+                ),
+            }
+        })?;
+    SafetySnapshot::prune(
+        &mapping.game_name,
+        (config.safety_snapshots_to_keep as usize).max(1),
+    );
+
+    let files = restore_files(&mut zip, &mapping, &plan);
+    let restored = files.iter().filter(|f| f.success).count() as u32;
+    Ok(RestoreReport {
+        game_name: mapping.game_name,
+        version_id: version.id,
+        target: backend.id(),
+        failed: files.len() as u32 - restored,
+        restored,
+        files,
+        safety_snapshot_id: Some(snapshot.id),
+    })
+}
+
+fn restore_files(
+    zip: &mut ZipArchive<File>,
+    mapping: &BackupMapping,
+    plan: &[PlannedFile],
+) -> Vec<RestoredFile> {
+    mapping
+        .files
+        .iter()
+        .zip(plan)
+        .map(|(file, planned)| {
+            let result = match (&planned.destination, &planned.root, &planned.error) {
+                (Some(dest), Some(root), None) => restore_file(zip, file, Path::new(dest), root),
+                (_, _, error) => Err(AppError::InvalidInput(
+                    error
+                        .clone()
+                        .unwrap_or_else(|| "No destination for this file".to_string()),
+                )
+                .into()),
+            };
+            if let Err(e) = &result {
+                log::warn!("Failed to restore {}: {}", file.archive_name, e);
+            }
+            let error = result.err().map(AppError::from);
+            RestoredFile {
+                archive_name: file.archive_name.clone(),
+                path: planned.destination.clone(),
+                success: error.is_none(),
+                error_code: error.as_ref().map(|e| e.code().to_string()),
+                error: error.map(|e| e.to_string()),
+            }
+        })
+        .collect()
+}
+
+/// Works out where every file of a backup goes on this machine, without
+/// writing anything. Redirect rules win; otherwise each file is placed below
+/// the placeholder it was backed up from, resolved for this OS. Windows
+/// saves restored on Linux go into whichever Proton prefix already has the
+/// most of the game's save folders.
+pub fn plan_restore(mapping: &BackupMapping, redirects: &[RedirectRule]) -> Vec<PlannedFile> {
+    let mut environments: HashMap<&str, Option<PathEnvironment>> = HashMap::new();
+    for file in &mapping.files {
+        let os = file_os(mapping, file);
+        if !environments.contains_key(os) {
+            environments.insert(os, choose_environment(mapping, os));
+        }
+    }
+
+    mapping
+        .files
+        .iter()
+        .map(|file| {
+            let os = file_os(mapping, file);
+            let environment = environments.get(os).and_then(Option::as_ref);
+            let resolved = resolve_file(file, os, redirects, environment).and_then(
+                |(dest, root, resolved_by)| {
+                    if !root.is_absolute() || dest == root || !paths::is_within(&root, &dest) {
+                        return Err(format!(
+                            "{} is outside the save location {}",
+                            dest.display(),
+                            root.display()
+                        ));
+                    }
+                    Ok((dest, root, resolved_by))
+                },
+            );
+
+            let mut planned = PlannedFile {
+                archive_name: file.archive_name.clone(),
+                original_path: file.original_path.clone(),
+                destination: None,
+                resolved_by: None,
+                error: None,
+                root: None,
+            };
+            match resolved {
+                Ok((dest, root, resolved_by)) => {
+                    planned.destination = Some(dest.to_string_lossy().to_string());
+                    planned.resolved_by = Some(resolved_by.to_string());
+                    planned.root = Some(root);
+                }
+                Err(e) => planned.error = Some(e),
+            }
+            planned
+        })
+        .collect()
+}
+
+fn file_os<'a>(mapping: &'a BackupMapping, file: &'a MappedFile) -> &'a str {
+    file.placeholder_os.as_deref().unwrap_or(&mapping.os)
+}
+
+/// Picks the environment for the files recorded with `os`'s layout. With
+/// several candidates, the one where most of the game's save folders
+/// already exist wins.
+fn choose_environment(mapping: &BackupMapping, os: &str) -> Option<PathEnvironment> {
+    let mut candidates = paths::environments_for(os);
+    if candidates.len() <= 1 {
+        return candidates.pop();
+    }
+
+    let score = |environment: &PathEnvironment| {
+        mapping
+            .files
+            .iter()
+            .filter(|file| file_os(mapping, file) == os)
+            .filter(|file| {
+                let (Some(placeholder), Some(save_root)) = (&file.placeholder, &file.save_root)
+                else {
+                    return false;
+                };
+                environment
+                    .placeholder_dir(placeholder)
+                    .and_then(|base| paths::join_within(&base, save_root))
+                    .is_some_and(|root| root.is_dir())
+            })
+            .count()
+    };
+    let scores: Vec<usize> = candidates.iter().map(score).collect();
+    let best = *scores.iter().max()?;
+    if best == 0 {
+        return None;
+    }
+    let index = scores.iter().position(|s| *s == best)?;
+    Some(candidates.swap_remove(index))
+}
+
+/// Destination, enclosing save folder, and how the destination was found.
+fn resolve_file(
+    file: &MappedFile,
+    os: &str,
+    redirects: &[RedirectRule],
+    environment: Option<&PathEnvironment>,
+) -> Result<(PathBuf, PathBuf, &'static str), String> {
+    if let Some((root, dest)) = paths::redirect(&file.original_path, redirects) {
+        return Ok((dest, root, "redirect"));
+    }
+
+    if let (Some(placeholder), Some(relative)) = (&file.placeholder, &file.relative_path) {
+        let environment = environment.ok_or_else(|| {
+            format!(
+                "Couldn't find where {} saves from {} belong on this machine; add a redirect rule",
+                placeholder, os
+            )
+        })?;
+        let base = environment
+            .placeholder_dir(placeholder)
+            .ok_or_else(|| format!("{} has no value on this machine", placeholder))?;
+        let dest = paths::join_within(&base, relative).ok_or("Unsafe path in backup mapping")?;
+        let root = match &file.save_root {
+            Some(save_root) => {
+                paths::join_within(&base, save_root).ok_or("Unsafe path in backup mapping")?
+            }
+            None => base,
+        };
+        return Ok((dest, root, environment.kind));
+    }
+
+    // Archives made before placeholders were recorded can only go back to
+    // their original path on the same kind of OS.
+    if (os == "windows") == cfg!(windows) {
+        return Ok((
+            PathBuf::from(&file.original_path),
+            paths::save_root(&file.pattern),
+            "original",
+        ));
+    }
+    Err(format!(
+        "{} is a {} path; add a redirect rule to restore it here",
+        file.original_path, os
+    ))
+}
+
+fn no_mapping(message: &str) -> AppError {
+    AppError::Io {
+        path: None,
+        message: message.to_string(),
+    }
+}
+
+fn invalid_destination() -> AppError {
+    AppError::InvalidInput("Invalid destination".to_string())
+}
+
+/// `path` with its nearest existing ancestor canonicalized. The components
+/// below that don't exist yet, so they can't be symlinks.
+fn resolve_symlinks(path: &Path) -> Result<PathBuf, AppError> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(canonical) => {
+                return Ok(missing.iter().rev().fold(canonical, |p, c| p.join(c)));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        missing.push(name);
+                        existing = parent;
+                    }
+                    _ => return Err(AppError::io(path, e)),
+                }
+            }
+            Err(e) => return Err(AppError::io(path, e)),
+        }
+    }
+}
+
+fn restore_file(
+    zip: &mut ZipArchive<File>,
+    file: &MappedFile,
+    dest: &Path,
+    root: &Path,
+) -> Result<(), BoxError> {
+    let mut data = Vec::new();
+    zip.by_name(&file.archive_name)?.read_to_end(&mut data)?;
+    if hex::encode(Sha256::digest(&data)) != file.sha256 {
+        return Err(AppError::Io {
+            path: None,
+            message: "File in the archive doesn't match its recorded checksum".to_string(),
+        }
+        .into());
+    }
+
+    let parent = dest.parent().ok_or_else(invalid_destination)?;
+    // Catch symlinked directories pointing out of the save location before
+    // creating anything through them.
+    if !resolve_symlinks(parent)?.starts_with(resolve_symlinks(root)?) {
+        return Err(AppError::InvalidInput(format!(
+            "{} resolves outside the save location",
+            parent.display()
+        ))
+        .into());
+    }
+    fs::create_dir_all(parent).map_err(|e| AppError::io(parent, e))?;
+
+    let file_name = dest.file_name().ok_or_else(invalid_destination)?;
+    let partial = parent.join(format!(
+        "{}.saveknight-restore",
+        file_name.to_string_lossy()
+    ));
+    fs::remove_file(&partial).ok();
+    let mut output = File::create(&partial).map_err(|e| AppError::io(dest, e))?;
+    output.write_all(&data).map_err(|e| AppError::io(dest, e))?;
+    if let Some(modified) = file.modified {
+        output.set_modified(SystemTime::from(modified)).ok();
+    }
+    output.sync_all().map_err(|e| AppError::io(dest, e))?;
+    drop(output);
+    fs::rename(&partial, dest).map_err(|e| AppError::io(dest, e))?;
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use crate::config::RetentionPolicy;
+use crate::storage::{GameRef, StorageBackend, StorageError, StoredVersion};
+use chrono::{DateTime, Datelike, Utc};
+use std::collections::HashSet;
+
+/// Identifies the day, week or month a version falls in.
+type PeriodKey = fn(&DateTime<Utc>) -> (i32, u32);
+
+/// Count rules of 0 would prune every version, so they are refused.
+pub fn validate(policy: &RetentionPolicy) -> Result<(), String> {
+    let rules = [
+        ("keep_last", policy.keep_last),
+        ("daily", policy.daily),
+        ("weekly", policy.weekly),
+        ("monthly", policy.monthly),
+    ];
+    match rules.iter().find(|(_, count)| *count == Some(0)) {
+        Some((name, _)) => Err(format!(
+            "Retention {} must be at least 1; leave it unset to not limit by it",
+            name
+        )),
+        None => Ok(()),
+    }
+}
+
+/// IDs of the versions `policy` doesn't keep. Versions without a creation
+/// time can't be placed in a day, week or month and are only subject to
+/// `keep_last` and the size limit.
+pub fn versions_to_prune(versions: &[StoredVersion], policy: &RetentionPolicy) -> Vec<String> {
+    let mut newest_first: Vec<&StoredVersion> = versions.iter().collect();
+    newest_first.sort_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then(b.version_number.cmp(&a.version_number))
+    });
+
+    let count_rules = [
+        policy.keep_last,
+        policy.daily,
+        policy.weekly,
+        policy.monthly,
+    ];
+    let mut kept: HashSet<&str> = if count_rules.iter().all(Option::is_none) {
+        newest_first.iter().map(|v| v.id.as_str()).collect()
+    } else {
+        let mut kept = HashSet::new();
+        if let Some(n) = policy.keep_last {
+            kept.extend(newest_first.iter().take(n as usize).map(|v| v.id.as_str()));
+        }
+        let periods: [(Option<u32>, PeriodKey); 3] = [
+            (policy.daily, |t| (t.year(), t.ordinal())),
+            (policy.weekly, |t| {
+                let week = t.iso_week();
+                (week.year(), week.week())
+            }),
+            (policy.monthly, |t| (t.year(), t.month())),
+        ];
+        for (count, period) in periods {
+            if let Some(n) = count {
+                kept.extend(newest_per_period(&newest_first, n as usize, period));
+            }
+        }
+        kept
+    };
+
+    if let Some(max_total_bytes) = policy.max_total_bytes {
+        // The newest kept version stays even if it alone is over the limit.
+        let mut total = 0;
+        let mut first_kept = true;
+        for version in &newest_first {
+            if !kept.contains(version.id.as_str()) {
+                continue;
+            }
+            total += version.size_bytes;
+            if total > max_total_bytes && !first_kept {
+                kept.remove(version.id.as_str());
+            }
+            first_kept = false;
+        }
+    }
+
+    newest_first
+        .iter()
+        .filter(|v| !kept.contains(v.id.as_str()))
+        .map(|v| v.id.clone())
+        .collect()
+}
+
+/// The newest version in each of the `count` most recent periods that have
+/// any versions.
+fn newest_per_period<'a>(
+    newest_first: &[&'a StoredVersion],
+    count: usize,
+    period: PeriodKey,
+) -> Vec<&'a str> {
+    let mut seen = HashSet::new();
+    newest_first
+        .iter()
+        .filter_map(|v| Some((period(v.created_at.as_ref()?), v.id.as_str())))
+        .filter(|(key, _)| seen.insert(*key))
+        .take(count)
+        .map(|(_, id)| id)
+        .collect()
+}
+
+/// Deletes the versions of a game that `policy` doesn't keep, reclaims the
+/// space they used, and returns their IDs. A version that fails to delete is
+/// logged and left for the next run.
+pub async fn apply(
+    backend: &dyn StorageBackend,
+    game: &GameRef,
+    policy: &RetentionPolicy,
+) -> Result<Vec<String>, StorageError> {
+    let versions = backend.list_versions(game).await?;
+    let mut pruned = Vec::new();
+    for id in versions_to_prune(&versions, policy) {
+        match backend.delete_version(game, &id).await {
+            Ok(()) => pruned.push(id),
+            Err(e) => log::warn!(
+                "Failed to prune version {} of {} from {}: {}",
+                id,
+                game.name,
+                backend.id(),
+                e
+            ),
+        }
+    }
+    if !pruned.is_empty() {
+        backend.compact().await?;
+        log::info!(
+            "Pruned {} version(s) of {} from {}",
+            pruned.len(),
+            game.name,
+            backend.id()
+        );
+    }
+    Ok(pruned)
+}
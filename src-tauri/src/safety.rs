@@ -0,0 +1,163 @@
+use crate::config::Config;
+use crate::error::AppError;
+use crate::restore::RestoredFile;
+use crate::uploader::sanitize_filename;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE_NAME: &str = "snapshot.json";
+
+/// Copy of a game's current save files, taken before a restore overwrites
+/// them so the restore can be undone. Lives entirely on this machine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafetySnapshot {
+    pub id: String,
+    pub game_name: String,
+    pub created_at: DateTime<Utc>,
+    /// The version whose restore this snapshot protects against.
+    pub restored_version_id: String,
+    pub files: Vec<SnapshotFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    pub path: String,
+    /// Name of the copy inside the snapshot, or `None` if the file didn't
+    /// exist before the restore and should be removed on undo.
+    pub stored_name: Option<String>,
+}
+
+impl SafetySnapshot {
+    fn game_dir(game_name: &str) -> PathBuf {
+        Config::data_dir()
+            .join("safety-snapshots")
+            .join(sanitize_filename(game_name))
+    }
+
+    fn dir(&self) -> PathBuf {
+        Self::game_dir(&self.game_name).join(&self.id)
+    }
+
+    /// Copies whatever currently exists at `paths`. The manifest is written
+    /// last, so a snapshot interrupted halfway is never offered for undo.
+    pub fn take(game_name: &str, restored_version_id: &str, paths: &[PathBuf]) -> io::Result<Self> {
+        let created_at = Utc::now();
+        let mut snapshot = SafetySnapshot {
+            id: format!(
+                "{}-{}",
+                created_at.format("%Y%m%dT%H%M%S%3fZ"),
+                uuid::Uuid::new_v4().simple()
+            ),
+            game_name: game_name.to_string(),
+            created_at,
+            restored_version_id: restored_version_id.to_string(),
+            files: Vec::with_capacity(paths.len()),
+        };
+        let dir = snapshot.dir();
+        fs::create_dir_all(&dir)?;
+
+        for (index, path) in paths.iter().enumerate() {
+            let stored_name = if path.is_file() {
+                let name = index.to_string();
+                if let Err(e) = reflink_copy::reflink_or_copy(path, dir.join(&name)) {
+                    fs::remove_dir_all(&dir).ok();
+                    return Err(e);
+                }
+                Some(name)
+            } else {
+                None
+            };
+            snapshot.files.push(SnapshotFile {
+                path: path.to_string_lossy().to_string(),
+                stored_name,
+            });
+        }
+
+        let manifest = serde_json::to_vec_pretty(&snapshot)?;
+        fs::write(dir.join(MANIFEST_FILE_NAME), manifest)?;
+        Ok(snapshot)
+    }
+
+    /// Snapshots of a game, newest first.
+    pub fn list(game_name: &str) -> Vec<Self> {
+        let mut snapshots: Vec<Self> = fs::read_dir(Self::game_dir(game_name))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let manifest = fs::read(entry.path().join(MANIFEST_FILE_NAME)).ok()?;
+                serde_json::from_slice(&manifest).ok()
+            })
+            .collect();
+        snapshots.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        snapshots
+    }
+
+    /// Removes all but the newest `keep` snapshots of a game, along with any
+    /// left incomplete.
+    pub fn prune(game_name: &str, keep: usize) {
+        let kept: Vec<String> = Self::list(game_name)
+            .into_iter()
+            .take(keep)
+            .map(|s| s.id)
+            .collect();
+        for entry in fs::read_dir(Self::game_dir(game_name))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !kept.contains(&name) {
+                if let Err(e) = fs::remove_dir_all(entry.path()) {
+                    log::warn!("Failed to remove safety snapshot {}: {}", name, e);
+                }
+            }
+        }
+    }
+
+    /// Puts every file back the way it was when the snapshot was taken.
+    pub fn undo(&self) -> Vec<RestoredFile> {
+        let dir = self.dir();
+        self.files
+            .iter()
+            .map(|file| {
+                let path = Path::new(&file.path);
+                let result = match &file.stored_name {
+                    Some(name) => put_back(&dir.join(name), path),
+                    None if path.exists() => fs::remove_file(path),
+                    None => Ok(()),
+                };
+                if let Err(e) = &result {
+                    log::warn!("Failed to undo restore of {}: {}", file.path, e);
+                }
+                let error = result.err().map(|e| AppError::io(path, e));
+                RestoredFile {
+                    archive_name: file.stored_name.clone().unwrap_or_default(),
+                    path: Some(file.path.clone()),
+                    success: error.is_none(),
+                    error_code: error.as_ref().map(|e| e.code().to_string()),
+                    error: error.map(|e| e.to_string()),
+                }
+            })
+            .collect()
+    }
+
+    pub fn delete(&self) -> io::Result<()> {
+        fs::remove_dir_all(self.dir())
+    }
+}
+
+fn put_back(stored: &Path, path: &Path) -> io::Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+    fs::create_dir_all(parent)?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = parent.join(format!("{}.saveknight-restore", file_name));
+    fs::remove_file(&partial).ok();
+    reflink_copy::reflink_or_copy(stored, &partial)?;
+    fs::rename(&partial, path)
+}
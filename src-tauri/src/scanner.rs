@@ -1,6 +1,7 @@
 use crate::ludusavi::{LudusaviManifest, SavePath};
+use crate::paths;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 use walkdir::WalkDir;
@@ -11,6 +12,33 @@ pub struct DetectedGame {
     pub paths: Vec<DetectedSavePath>,
     pub total_size_bytes: u64,
     pub last_modified: Option<String>,
+    #[serde(default)]
+    pub store_ids: StoreIds,
+}
+
+/// IDs of a game in the stores the manifest knows it from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreIds {
+    pub steam: Option<u64>,
+    pub gog: Option<u64>,
+}
+
+impl StoreIds {
+    pub fn is_empty(&self) -> bool {
+        self.steam.is_none() && self.gog.is_none()
+    }
+
+    /// Whether both have the same ID in some store.
+    pub fn matches(&self, other: &StoreIds) -> bool {
+        let same = |a: Option<u64>, b: Option<u64>| a.is_some() && a == b;
+        same(self.steam, other.steam) || same(self.gog, other.gog)
+    }
+
+    /// Whether both are in the same store under different IDs.
+    pub fn conflicts(&self, other: &StoreIds) -> bool {
+        let differ = |a: Option<u64>, b: Option<u64>| a.is_some() && b.is_some() && a != b;
+        differ(self.steam, other.steam) || differ(self.gog, other.gog)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +50,15 @@ pub struct DetectedSavePath {
     pub total_size_bytes: u64,
 }
 
+/// A save file currently on disk, as found through one of a game's patterns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalSaveFile {
+    pub path: PathBuf,
+    pub pattern: String,
+    pub size_bytes: u64,
+    pub modified: Option<std::time::SystemTime>,
+}
+
 pub struct Scanner {
     manifest: LudusaviManifest,
 }
@@ -87,6 +124,7 @@ impl Scanner {
             paths: detected_paths,
             total_size_bytes: total_size,
             last_modified,
+            store_ids: self.manifest.store_ids(game_name),
         })
     }
 
@@ -125,29 +163,31 @@ impl Scanner {
         }
     }
 
+    /// Every file below a detected game's save paths. Files matched by more
+    /// than one pattern are listed once.
+    pub fn inventory(game: &DetectedGame) -> Vec<LocalSaveFile> {
+        let mut files = Vec::new();
+        let mut seen = HashSet::new();
+        for detected_path in game.paths.iter().filter(|p| p.exists) {
+            for entry in glob::glob(&detected_path.resolved_path).into_iter().flatten().flatten() {
+                for file_entry in WalkDir::new(&entry).into_iter().filter_map(|e| e.ok()) {
+                    if !file_entry.file_type().is_file() || !seen.insert(file_entry.path().to_path_buf()) {
+                        continue;
+                    }
+                    let metadata = file_entry.metadata().ok();
+                    files.push(LocalSaveFile {
+                        path: file_entry.into_path(),
+                        pattern: detected_path.pattern.clone(),
+                        size_bytes: metadata.as_ref().map(|m| m.len()).unwrap_or(0),
+                        modified: metadata.and_then(|m| m.modified().ok()),
+                    });
+                }
+            }
+        }
+        files
+    }
+
     fn resolve_glob_path(&self, path: &str) -> String {
-        let home = dirs::home_dir()
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_default();
-        let documents = dirs::document_dir()
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_default();
-        let appdata = dirs::data_dir()
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_default();
-        let local_appdata = dirs::data_local_dir()
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_default();
-        let username = std::env::var("USERNAME")
-            .or_else(|_| std::env::var("USER"))
-            .unwrap_or_else(|_| "user".to_string());
-
-        path.replace("<home>", &home)
-            .replace("<documents>", &documents)
-            .replace("<appData>", &appdata)
-            .replace("<localAppData>", &local_appdata)
-            .replace("<storeUserId>", "*")
-            .replace("<osUserName>", &username)
-            .replace('/', std::path::MAIN_SEPARATOR_STR)
+        paths::resolve_pattern(path)
     }
 }
@@ -0,0 +1,92 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+pub struct StagedFile {
+    pub source: PathBuf,
+    pub staged: PathBuf,
+    pub modified: Option<SystemTime>,
+    /// False when the source kept changing while it was copied, so the
+    /// staged copy may be torn.
+    pub consistent: bool,
+}
+
+/// Private copy of a game's save files, taken before archiving so the game
+/// can keep writing while the archive is built. Removed when dropped.
+pub struct StagingArea {
+    root: PathBuf,
+}
+
+impl StagingArea {
+    pub fn create(root: PathBuf) -> io::Result<Self> {
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Copies `source` into the staging area, using a reflink when the
+    /// filesystem supports it. The copy is retried if the source's size or
+    /// modification time changed while it was being copied.
+    pub fn stage_file(&self, index: usize, source: &Path) -> io::Result<StagedFile> {
+        let target = self.root.join(index.to_string());
+        let mut last_modified = None;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let before = fingerprint(source)?;
+            if target.exists() {
+                fs::remove_file(&target)?;
+            }
+            reflink_copy::reflink_or_copy(source, &target)?;
+            let after = fingerprint(source)?;
+            let copied = fs::metadata(&target)?.len();
+
+            if before == after && copied == after.0 {
+                return Ok(StagedFile {
+                    source: source.to_path_buf(),
+                    staged: target,
+                    modified: after.1,
+                    consistent: true,
+                });
+            }
+
+            log::warn!(
+                "{} changed while it was being copied (attempt {}/{})",
+                source.display(),
+                attempt,
+                MAX_ATTEMPTS
+            );
+            last_modified = after.1;
+            if attempt < MAX_ATTEMPTS {
+                std::thread::sleep(RETRY_DELAY * attempt);
+            }
+        }
+
+        Ok(StagedFile {
+            source: source.to_path_buf(),
+            staged: target,
+            modified: last_modified,
+            consistent: false,
+        })
+    }
+}
+
+impl Drop for StagingArea {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.root).ok();
+    }
+}
+
+fn fingerprint(path: &Path) -> io::Result<(u64, Option<SystemTime>)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.len(), metadata.modified().ok()))
+}
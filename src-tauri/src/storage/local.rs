@@ -0,0 +1,301 @@
+use super::{
+    check_version_id, game_component, new_version_id, next_version_number, GameRef, StorageBackend,
+    StorageError, StoredVersion,
+};
+use crate::chunkstore::{ChunkStore, GcReport, SnapshotManifest, VerifyReport};
+use crate::progress::ProgressReporter;
+use crate::queue::UploadJob;
+use async_trait::async_trait;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the chunk store directory below the root of a dedup target.
+const CHUNK_STORE_DIR: &str = ".saveknight-chunks";
+
+/// Keeps versions in a local or network folder, one directory per game:
+/// `<root>/<game>/<version id>.zip` plus a `<version id>.json` sidecar with
+/// the version metadata. With dedup enabled, a `<version id>.manifest`
+/// pointing into the root's chunk store takes the place of the zip.
+#[derive(Clone)]
+pub struct LocalBackend {
+    name: String,
+    root: PathBuf,
+    dedup: bool,
+}
+
+impl LocalBackend {
+    pub fn new(name: &str, root: &Path, dedup: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            root: root.to_path_buf(),
+            dedup,
+        }
+    }
+
+    /// Opens the chunk store, which holds the store lock until it's dropped.
+    fn chunk_store(&self) -> Result<ChunkStore, StorageError> {
+        Ok(ChunkStore::open(&self.root.join(CHUNK_STORE_DIR))?)
+    }
+
+    /// Every snapshot manifest below the root, named `<game dir>/<id>`.
+    fn manifests(&self) -> Result<Vec<(String, SnapshotManifest)>, StorageError> {
+        let mut manifests = Vec::new();
+        for game_dir in fs::read_dir(&self.root)? {
+            let game_dir = game_dir?.path();
+            if !game_dir.is_dir() || game_dir.ends_with(CHUNK_STORE_DIR) {
+                continue;
+            }
+            for entry in fs::read_dir(&game_dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("manifest") {
+                    continue;
+                }
+                let manifest = fs::read(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()))
+                    .map_err(|e| {
+                        StorageError::Rejected(format!(
+                            "Unreadable snapshot manifest {}: {}",
+                            path.display(),
+                            e
+                        ))
+                    })?;
+                let name = path
+                    .strip_prefix(&self.root)
+                    .unwrap_or(&path)
+                    .with_extension("")
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                manifests.push((name, manifest));
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// Reads back every chunk of a dedup target and reports any damage.
+    pub fn verify_store(&self) -> Result<VerifyReport, StorageError> {
+        self.check_root()?;
+        let store = self.chunk_store()?;
+        let manifests = self.manifests()?;
+        Ok(store.verify(&manifests))
+    }
+
+    /// Removes chunks no version refers to any more.
+    pub fn gc_store(&self) -> Result<GcReport, StorageError> {
+        self.check_root()?;
+        // Manifests are listed under the store lock, so a version being
+        // stored concurrently is either complete or hasn't written chunks.
+        let mut store = self.chunk_store()?;
+        // A manifest that can't be read makes it impossible to tell which
+        // chunks are still needed, so `manifests` fails rather than skipping.
+        let manifests: Vec<SnapshotManifest> =
+            self.manifests()?.into_iter().map(|(_, m)| m).collect();
+        Ok(store.gc(&manifests)?)
+    }
+
+    fn game_dir(&self, game_name: &str) -> Result<PathBuf, StorageError> {
+        Ok(self.root.join(game_component(game_name)?))
+    }
+
+    fn read_versions(&self, game_name: &str) -> Result<Vec<StoredVersion>, StorageError> {
+        let dir = self.game_dir(game_name)?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            match fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| {
+                    serde_json::from_str::<StoredVersion>(&content).map_err(|e| e.to_string())
+                }) {
+                Ok(version) => versions.push(version),
+                Err(e) => log::warn!(
+                    "Skipping unreadable version metadata {}: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+
+        versions.sort_by_key(|v| v.version_number);
+        Ok(versions)
+    }
+
+    fn version_path(
+        &self,
+        game_name: &str,
+        version_id: &str,
+        extension: &str,
+    ) -> Result<PathBuf, StorageError> {
+        check_version_id(version_id)?;
+        Ok(self
+            .game_dir(game_name)?
+            .join(format!("{}.{}", version_id, extension)))
+    }
+
+    fn write_version(&self, job: &UploadJob) -> Result<StoredVersion, StorageError> {
+        let dir = self.game_dir(&job.game_name)?;
+        fs::create_dir_all(&dir)?;
+
+        let version_number = next_version_number(&self.read_versions(&job.game_name)?);
+        let id = new_version_id();
+
+        let mut version = StoredVersion::for_job(job, id.clone(), version_number);
+        if self.dedup {
+            // Keep the store (and its lock) until the manifest is in place,
+            // so gc can't run in between and drop the new chunks.
+            let mut store = self.chunk_store()?;
+            let manifest = store.store_archive(Path::new(&job.archive_path))?;
+            if matches!(manifest, SnapshotManifest::Zip { .. }) {
+                // The zip is rebuilt on download, so its bytes won't match
+                // the original's checksum; each entry is verified instead.
+                version.checksum = None;
+            }
+            let manifest_path = dir.join(format!("{}.manifest", id));
+            let partial_path = dir.join(format!("{}.manifest.partial", id));
+            let data =
+                serde_json::to_vec(&manifest).map_err(|e| StorageError::Rejected(e.to_string()))?;
+            fs::write(&partial_path, data)?;
+            fs::rename(&partial_path, &manifest_path)?;
+            drop(store);
+        } else {
+            let archive_path = dir.join(format!("{}.zip", id));
+            let partial_path = dir.join(format!("{}.zip.partial", id));
+            fs::copy(&job.archive_path, &partial_path)?;
+            fs::rename(&partial_path, &archive_path)?;
+        }
+
+        let metadata = serde_json::to_string_pretty(&version)
+            .map_err(|e| StorageError::Rejected(e.to_string()))?;
+        fs::write(dir.join(format!("{}.json", id)), metadata)?;
+
+        Ok(version)
+    }
+
+    fn check_root(&self) -> Result<(), StorageError> {
+        // A missing root usually means an unmounted drive or NAS share, which
+        // may come back, so don't create it and treat it as transient.
+        if self.root.is_dir() {
+            Ok(())
+        } else {
+            Err(StorageError::Transient(format!(
+                "Backup folder {} is not available",
+                self.root.display()
+            )))
+        }
+    }
+
+    fn read_version(
+        &self,
+        game_name: &str,
+        version_id: &str,
+        dest: &Path,
+    ) -> Result<StoredVersion, StorageError> {
+        let version = self
+            .read_versions(game_name)?
+            .into_iter()
+            .find(|v| v.id == version_id)
+            .ok_or_else(|| StorageError::Rejected(format!("Version {} not found", version_id)))?;
+
+        // Versions stored before dedup was turned on are still plain zips.
+        let manifest_path = self.version_path(game_name, &version.id, "manifest")?;
+        if manifest_path.is_file() {
+            let manifest: SnapshotManifest = serde_json::from_slice(&fs::read(&manifest_path)?)
+                .map_err(|e| StorageError::Rejected(e.to_string()))?;
+            self.chunk_store()?.rebuild(&manifest, dest)?;
+        } else {
+            fs::copy(self.version_path(game_name, &version.id, "zip")?, dest)?;
+        }
+        Ok(version)
+    }
+
+    /// `e`, unless the root went missing while it was in use, in which case
+    /// the drive was likely unmounted and the failure is transient.
+    fn unless_root_lost(&self, e: StorageError) -> StorageError {
+        self.check_root().err().unwrap_or(e)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    fn id(&self) -> String {
+        format!("local:{}", self.name)
+    }
+
+    async fn put_version(
+        &self,
+        job: &UploadJob,
+        progress: &ProgressReporter,
+    ) -> Result<StoredVersion, StorageError> {
+        self.check_root()?;
+        progress.upload_started(job.size_bytes);
+        // Copying, chunking and compressing are all blocking file work.
+        let backend = self.clone();
+        let owned_job = job.clone();
+        let version = tokio::task::spawn_blocking(move || backend.write_version(&owned_job))
+            .await
+            .map_err(|e| StorageError::Rejected(e.to_string()))?
+            .map_err(|e| self.unless_root_lost(e))?;
+        progress.bytes_sent(job.size_bytes);
+        Ok(version)
+    }
+
+    async fn list_versions(&self, game: &GameRef) -> Result<Vec<StoredVersion>, StorageError> {
+        self.check_root()?;
+        self.read_versions(&game.name)
+    }
+
+    fn archive_path(&self, game: &GameRef, version_id: &str) -> Option<PathBuf> {
+        let path = self.version_path(&game.name, version_id, "zip").ok()?;
+        path.is_file().then_some(path)
+    }
+
+    async fn get_version(
+        &self,
+        game: &GameRef,
+        version_id: &str,
+        dest: &Path,
+    ) -> Result<StoredVersion, StorageError> {
+        self.check_root()?;
+        // Rebuilding from chunks or copying the zip is blocking file work.
+        let backend = self.clone();
+        let game_name = game.name.clone();
+        let version_id = version_id.to_string();
+        let dest = dest.to_path_buf();
+        tokio::task::spawn_blocking(move || backend.read_version(&game_name, &version_id, &dest))
+            .await
+            .map_err(|e| StorageError::Rejected(e.to_string()))?
+            .map_err(|e| self.unless_root_lost(e))
+    }
+
+    async fn delete_version(&self, game: &GameRef, version_id: &str) -> Result<(), StorageError> {
+        self.check_root()?;
+        let metadata_path = self.version_path(&game.name, version_id, "json")?;
+        if !metadata_path.exists() {
+            return Err(StorageError::Rejected(format!(
+                "Version {} not found",
+                version_id
+            )));
+        }
+        fs::remove_file(self.version_path(&game.name, version_id, "zip")?).ok();
+        fs::remove_file(self.version_path(&game.name, version_id, "manifest")?).ok();
+        fs::remove_file(metadata_path)?;
+        Ok(())
+    }
+
+    async fn compact(&self) -> Result<(), StorageError> {
+        if self.dedup {
+            let backend = self.clone();
+            tokio::task::spawn_blocking(move || backend.gc_store())
+                .await
+                .map_err(|e| StorageError::Rejected(e.to_string()))??;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+mod local;
+mod s3;
+mod saveknight;
+mod webdav;
+
+pub use local::LocalBackend;
+pub use s3::S3Backend;
+pub use saveknight::SaveKnightBackend;
+pub use webdav::WebDavBackend;
+
+use crate::api::KEYRING_SERVICE;
+use crate::client::{ApiClient, ApiError, TRANSFER_IDLE_TIMEOUT};
+use crate::config::{Config, StorageTarget};
+use crate::mapping::BackupMapping;
+use crate::progress::ProgressReporter;
+use crate::queue::UploadJob;
+use crate::throttle::BandwidthLimiter;
+use crate::uploader::sanitize_filename;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use keyring::Entry;
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    /// The backend couldn't be reached or failed temporarily; retry later.
+    #[error("{0}")]
+    Transient(String),
+    /// The backend refused the request and retrying won't help.
+    #[error("{0}")]
+    Rejected(String),
+    /// The SaveKnight API failed, kept whole so its status and error code
+    /// reach the frontend.
+    #[error("{0}")]
+    Api(ApiError),
+}
+
+impl StorageError {
+    /// Whether the upload should stay queued and be tried again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            StorageError::Transient(_) => true,
+            StorageError::Rejected(_) => false,
+            // Uploads wait for the device to be signed in again.
+            StorageError::Api(e) => e.is_transient() || matches!(e, ApiError::Auth(_)),
+        }
+    }
+
+    /// Whether the target couldn't be reached or failed on its end, as
+    /// opposed to refusing the request. Unlike [`Self::is_transient`], a
+    /// signed out device doesn't count.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            StorageError::Transient(_) => true,
+            StorageError::Rejected(_) => false,
+            StorageError::Api(e) => e.is_transient(),
+        }
+    }
+}
+
+impl From<ApiError> for StorageError {
+    fn from(e: ApiError) -> Self {
+        StorageError::Api(e)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            // A network share that dropped out or a busy file; worth retrying.
+            ErrorKind::NotConnected
+            | ErrorKind::TimedOut
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock => StorageError::Transient(e.to_string()),
+            _ => StorageError::Rejected(e.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(e: reqwest::Error) -> Self {
+        StorageError::Transient(e.to_string())
+    }
+}
+
+/// The game a version belongs to. The SaveKnight API addresses games by
+/// profile ID, other backends by name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameRef {
+    pub name: String,
+    pub profile_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredVersion {
+    pub id: String,
+    pub version_number: Option<i32>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub size_bytes: u64,
+    pub checksum: Option<String>,
+    pub slot_name: Option<String>,
+    pub device_name: Option<String>,
+    pub key_id: Option<String>,
+    pub mapping: Option<BackupMapping>,
+}
+
+impl StoredVersion {
+    /// Metadata for a version of `job` stored by a backend that keeps its own
+    /// `<id>.json` sidecar next to the archive.
+    fn for_job(job: &UploadJob, id: String, version_number: i32) -> Self {
+        StoredVersion {
+            id,
+            version_number: Some(version_number),
+            created_at: Some(Utc::now()),
+            size_bytes: job.size_bytes,
+            checksum: Some(job.checksum.clone()),
+            slot_name: Some(format!("{} Auto-Backup", job.game_name)),
+            device_name: None,
+            key_id: job.key_id.clone(),
+            mapping: job.mapping.clone(),
+        }
+    }
+}
+
+/// One page of a game's version history, newest first. Pages start at 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionPage {
+    pub versions: Vec<StoredVersion>,
+    pub page: u32,
+    pub page_size: u32,
+    pub total: Option<u64>,
+    pub has_more: bool,
+}
+
+impl VersionPage {
+    /// Cuts a page out of a full history sorted oldest first.
+    fn from_all(mut versions: Vec<StoredVersion>, page: u32, page_size: u32) -> Self {
+        versions.reverse();
+        let total = versions.len();
+        let start = (page.max(1) as usize - 1).saturating_mul(page_size as usize);
+        let versions: Vec<StoredVersion> = versions
+            .into_iter()
+            .skip(start)
+            .take(page_size as usize)
+            .collect();
+        VersionPage {
+            has_more: start + versions.len() < total,
+            versions,
+            page,
+            page_size,
+            total: Some(total as u64),
+        }
+    }
+}
+
+/// Somewhere backup versions can be kept.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Stable identifier, matching [`StorageTarget::id`].
+    fn id(&self) -> String;
+
+    async fn put_version(
+        &self,
+        job: &UploadJob,
+        progress: &ProgressReporter,
+    ) -> Result<StoredVersion, StorageError>;
+
+    async fn list_versions(&self, game: &GameRef) -> Result<Vec<StoredVersion>, StorageError>;
+
+    /// One page of the version history, newest first. Backends without
+    /// server-side paging list everything and slice it.
+    async fn list_versions_page(
+        &self,
+        game: &GameRef,
+        page: u32,
+        page_size: u32,
+    ) -> Result<VersionPage, StorageError> {
+        let versions = self.list_versions(game).await?;
+        Ok(VersionPage::from_all(versions, page, page_size))
+    }
+
+    /// Path of a version's archive if it can be read in place, so it
+    /// doesn't need to be downloaded first.
+    fn archive_path(&self, _game: &GameRef, _version_id: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Downloads the archive of a version to `dest`.
+    async fn get_version(
+        &self,
+        game: &GameRef,
+        version_id: &str,
+        dest: &Path,
+    ) -> Result<StoredVersion, StorageError>;
+
+    async fn delete_version(&self, game: &GameRef, version_id: &str) -> Result<(), StorageError>;
+
+    /// Reclaims space freed by deleted versions, for backends that share
+    /// data between versions.
+    async fn compact(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// Builds a backend for every configured target. Targets without their
+/// credentials (the SaveKnight device token or a stored secret) are skipped.
+pub fn backends_from_config(
+    config: &Config,
+    device_token: Option<&str>,
+    limiter: Option<Arc<BandwidthLimiter>>,
+) -> Vec<Arc<dyn StorageBackend>> {
+    let mut backends: Vec<Arc<dyn StorageBackend>> = Vec::new();
+
+    for target in &config.storage_targets {
+        match target {
+            StorageTarget::SaveKnight => {
+                if let Some(token) = device_token {
+                    backends.push(Arc::new(SaveKnightBackend::new(
+                        ApiClient::new(config).with_token(token),
+                        limiter.clone(),
+                    )));
+                }
+            }
+            StorageTarget::Local { name, path, dedup } => {
+                backends.push(Arc::new(LocalBackend::new(name, Path::new(path), *dedup)));
+            }
+            StorageTarget::S3 { .. } => match load_secret(&target.id()) {
+                Some(secret) => match S3Backend::new(target, &secret, limiter.clone()) {
+                    Ok(backend) => backends.push(Arc::new(backend)),
+                    Err(e) => log::warn!("Skipping storage target {}: {}", target.id(), e),
+                },
+                None => log::warn!("No secret stored for storage target {}", target.id()),
+            },
+            StorageTarget::WebDav { .. } => match load_secret(&target.id()) {
+                Some(password) => match WebDavBackend::new(target, &password, limiter.clone()) {
+                    Ok(backend) => backends.push(Arc::new(backend)),
+                    Err(e) => log::warn!("Skipping storage target {}: {}", target.id(), e),
+                },
+                None => log::warn!("No password stored for storage target {}", target.id()),
+            },
+        }
+    }
+
+    backends
+}
+
+/// Checks a target's settings before they are saved.
+pub fn validate_target(target: &StorageTarget) -> Result<(), String> {
+    match target {
+        StorageTarget::SaveKnight => Ok(()),
+        StorageTarget::Local { name, path, .. } => {
+            if name.is_empty() || path.is_empty() {
+                return Err("Local targets need a name and a path".to_string());
+            }
+            Ok(())
+        }
+        StorageTarget::S3 { name, bucket, .. } => {
+            if name.is_empty() || bucket.is_empty() {
+                return Err("S3 targets need a name and a bucket".to_string());
+            }
+            S3Backend::new(target, "", None).map(|_| ())
+        }
+        StorageTarget::WebDav { name, username, .. } => {
+            if name.is_empty() || username.is_empty() {
+                return Err("WebDAV targets need a name and a username".to_string());
+            }
+            WebDavBackend::new(target, "", None).map(|_| ())
+        }
+    }
+}
+
+fn secret_entry(target_id: &str) -> Result<Entry, String> {
+    Entry::new(KEYRING_SERVICE, &format!("storage-secret:{}", target_id))
+        .map_err(|e| format!("Keyring error: {}", e))
+}
+
+/// Stores the password or secret key of a storage target in the keyring.
+pub fn store_secret(target_id: &str, secret: &str) -> Result<(), String> {
+    secret_entry(target_id)?
+        .set_password(secret)
+        .map_err(|e| format!("Failed to store secret: {}", e))
+}
+
+fn load_secret(target_id: &str) -> Option<String> {
+    secret_entry(target_id).ok()?.get_password().ok()
+}
+
+/// Version IDs end up in file names and object keys, so only plain names
+/// are accepted.
+fn check_version_id(version_id: &str) -> Result<(), StorageError> {
+    if version_id.is_empty() || version_id.contains(['/', '\\']) || version_id.contains("..") {
+        return Err(StorageError::Rejected(format!(
+            "Invalid version id {}",
+            version_id
+        )));
+    }
+    Ok(())
+}
+
+/// A game's directory name or key segment: its sanitized name, which has to
+/// be one ordinary path component so that names like `..` can't reach
+/// outside the target.
+fn game_component(game_name: &str) -> Result<String, StorageError> {
+    let name = sanitize_filename(game_name);
+    let mut components = Path::new(&name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None) if component == name.as_str() => Ok(name),
+        _ => Err(StorageError::Rejected(format!(
+            "Invalid game name {:?}",
+            game_name
+        ))),
+    }
+}
+
+/// New version IDs are UTC timestamps, so they sort chronologically.
+fn new_version_id() -> String {
+    Utc::now().format("%Y%m%dT%H%M%S%3fZ").to_string()
+}
+
+fn next_version_number(existing: &[StoredVersion]) -> i32 {
+    existing
+        .iter()
+        .filter_map(|v| v.version_number)
+        .max()
+        .unwrap_or(0)
+        + 1
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Streams a download into `dest`.
+async fn save_response(response: Response, dest: &Path) -> Result<(), StorageError> {
+    let mut file = tokio::fs::File::create(dest).await?;
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = tokio::time::timeout(TRANSFER_IDLE_TIMEOUT, stream.try_next())
+        .await
+        .map_err(|_| {
+            StorageError::Transient(format!(
+                "The download stalled: nothing was received for {} seconds",
+                TRANSFER_IDLE_TIMEOUT.as_secs()
+            ))
+        })??
+    {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` only when
+/// `encode_slash` is set. This is the encoding SigV4 expects, and it is also
+/// safe for WebDAV paths.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,361 @@
+use super::{save_response, GameRef, StorageBackend, StorageError, StoredVersion, VersionPage};
+use crate::client::{
+    ApiClient, DeltaFile, DeltaUploadRequest, SaveVersion, UploadRequest, UploadResponse,
+    UploadSource, VersionsResponse,
+};
+use crate::mapping::{BackupMapping, MappedFile};
+use crate::progress::ProgressReporter;
+use crate::queue::UploadJob;
+use crate::throttle::BandwidthLimiter;
+use crate::uploader::sanitize_filename;
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use zip::ZipArchive;
+
+impl From<SaveVersion> for StoredVersion {
+    fn from(v: SaveVersion) -> Self {
+        StoredVersion {
+            id: v.id,
+            version_number: Some(v.version_number),
+            created_at: v.created_at,
+            size_bytes: v.file_size.unwrap_or_default(),
+            checksum: v.checksum,
+            slot_name: v.slot_name,
+            device_name: v.device_name,
+            key_id: v.key_id,
+            mapping: v.mapping,
+        }
+    }
+}
+
+const LIST_PAGE_SIZE: u32 = 100;
+
+/// The hosted SaveKnight API. Servers advertising delta uploads only receive
+/// the files they don't already have.
+pub struct SaveKnightBackend {
+    client: ApiClient,
+    bandwidth_limiter: Option<Arc<BandwidthLimiter>>,
+    delta_uploads: OnceCell<bool>,
+}
+
+impl SaveKnightBackend {
+    pub fn new(client: ApiClient, bandwidth_limiter: Option<Arc<BandwidthLimiter>>) -> Self {
+        Self {
+            client,
+            bandwidth_limiter,
+            delta_uploads: OnceCell::new(),
+        }
+    }
+
+    fn profile_id<'a>(&self, game: &'a GameRef) -> Result<&'a str, StorageError> {
+        game.profile_id
+            .as_deref()
+            .ok_or_else(|| StorageError::Rejected(format!("{} has no game profile", game.name)))
+    }
+
+    /// Fetches one page of a game profile's versions, newest first.
+    async fn fetch_versions(
+        &self,
+        game: &GameRef,
+        page: u32,
+        page_size: u32,
+    ) -> Result<VersionsResponse, StorageError> {
+        Ok(self
+            .client
+            .versions(self.profile_id(game)?, page, page_size)
+            .await?)
+    }
+
+    /// Fetches page `page` of `LIST_PAGE_SIZE` versions, and whether it is
+    /// the last one.
+    async fn fetch_listing_page(
+        &self,
+        game: &GameRef,
+        page: u32,
+    ) -> Result<(Vec<SaveVersion>, bool), StorageError> {
+        let response = self.fetch_versions(game, page, LIST_PAGE_SIZE).await?;
+        Ok(match response {
+            VersionsResponse::All(all) => (all, true),
+            VersionsResponse::Page {
+                versions, has_more, ..
+            } => {
+                let last = versions.is_empty()
+                    || has_more == Some(false)
+                    || (has_more.is_none() && versions.len() < LIST_PAGE_SIZE as usize);
+                (versions, last)
+            }
+        })
+    }
+
+    /// Whether the server accepts delta uploads. Servers that predate the
+    /// capabilities endpoint don't.
+    async fn supports_delta_uploads(&self) -> bool {
+        if let Some(supported) = self.delta_uploads.get() {
+            return *supported;
+        }
+        let supported = match self.client.capabilities().await {
+            Ok(capabilities) => capabilities.delta_uploads,
+            Err(e) if e.status() == Some(404) => false,
+            // Ask again next time rather than remembering a network error.
+            Err(_) => return false,
+        };
+        *self.delta_uploads.get_or_init(|| supported)
+    }
+
+    /// Uploads only the files the server doesn't have yet: sends the file
+    /// manifest, uploads each missing blob, then commits the version.
+    /// Returns `None` if the server turned the delta upload down, in which
+    /// case the whole archive should be uploaded instead.
+    async fn put_delta(
+        &self,
+        job: &UploadJob,
+        mapping: &BackupMapping,
+        progress: &ProgressReporter,
+    ) -> Result<Option<StoredVersion>, StorageError> {
+        let request = DeltaUploadRequest {
+            slot_name: format!("{} Auto-Backup", job.game_name),
+            local_path: &job.local_path,
+            checksum: &job.checksum,
+            mapping,
+            files: mapping
+                .files
+                .iter()
+                .map(|f| DeltaFile {
+                    name: &f.archive_name,
+                    sha256: &f.sha256,
+                    size: f.size_bytes,
+                })
+                .collect(),
+        };
+        let session = match self
+            .client
+            .start_delta_upload(&job.game_profile_id, &request)
+            .await
+        {
+            Ok(session) => session,
+            Err(e) if matches!(e.status(), Some(404 | 405 | 501)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let present: HashSet<&str> = session.present.iter().map(String::as_str).collect();
+        let mut missing: Vec<&MappedFile> = Vec::new();
+        for file in &mapping.files {
+            let already_listed = missing.iter().any(|m| m.sha256 == file.sha256);
+            if !present.contains(file.sha256.as_str()) && !already_listed {
+                missing.push(file);
+            }
+        }
+        let missing_bytes: u64 = missing.iter().map(|f| f.size_bytes).sum();
+        log::info!(
+            "Delta upload of {}: {} of {} file(s) missing on the server ({} bytes)",
+            job.game_name,
+            missing.len(),
+            mapping.files.len(),
+            missing_bytes
+        );
+
+        progress.upload_started(missing_bytes);
+        let archive_path = Path::new(&job.archive_path);
+        let blob_path = archive_path.with_extension("blob");
+        for file in missing {
+            let extracted = extract_entry(archive_path, &file.archive_name, &blob_path).await;
+            let result = match extracted {
+                Ok(()) => {
+                    let source = UploadSource {
+                        path: &blob_path,
+                        size_bytes: file.size_bytes,
+                        limiter: self.bandwidth_limiter.clone(),
+                        progress,
+                    };
+                    self.client
+                        .put_delta_blob(&session.upload_id, &file.sha256, &source)
+                        .await
+                        .map_err(StorageError::from)
+                }
+                Err(e) => Err(StorageError::Rejected(format!(
+                    "Couldn't read {} from the staged archive: {}",
+                    file.archive_name, e
+                ))),
+            };
+            tokio::fs::remove_file(&blob_path).await.ok();
+            result?;
+        }
+
+        let result = self
+            .client
+            .commit_delta_upload(&job.game_profile_id, &session.upload_id)
+            .await?;
+        Ok(Some(uploaded_version(result)?))
+    }
+}
+
+fn uploaded_version(result: UploadResponse) -> Result<StoredVersion, StorageError> {
+    let version = result
+        .save_version
+        .ok_or_else(|| StorageError::Rejected("Server did not return a version".to_string()))?;
+    Ok(version.into())
+}
+
+#[async_trait]
+impl StorageBackend for SaveKnightBackend {
+    fn id(&self) -> String {
+        "saveknight".to_string()
+    }
+
+    async fn put_version(
+        &self,
+        job: &UploadJob,
+        progress: &ProgressReporter,
+    ) -> Result<StoredVersion, StorageError> {
+        // Delta uploads send files in the clear, so encrypted archives always
+        // go up whole.
+        if let (None, Some(mapping)) = (&job.key_id, &job.mapping) {
+            if self.supports_delta_uploads().await {
+                if let Some(version) = self.put_delta(job, mapping, progress).await? {
+                    return Ok(version);
+                }
+                log::info!("Server declined the delta upload; uploading the whole archive");
+            }
+        }
+
+        let archive_path = Path::new(&job.archive_path);
+        let file_size = std::fs::metadata(archive_path)
+            .map_err(|e| StorageError::Rejected(format!("Staged archive is missing: {}", e)))?
+            .len();
+
+        let (file_name, mime) = match &job.key_id {
+            Some(_) => (
+                format!("{}.zip.enc", sanitize_filename(&job.game_name)),
+                "application/octet-stream",
+            ),
+            None => (
+                format!("{}.zip", sanitize_filename(&job.game_name)),
+                "application/zip",
+            ),
+        };
+        let upload = UploadRequest {
+            slot_name: format!("{} Auto-Backup", job.game_name),
+            local_path: &job.local_path,
+            checksum: &job.checksum,
+            mapping: job.mapping.as_ref(),
+            key_id: job.key_id.as_deref(),
+            file_name,
+            mime,
+        };
+        let source = UploadSource {
+            path: archive_path,
+            size_bytes: file_size,
+            limiter: self.bandwidth_limiter.clone(),
+            progress,
+        };
+
+        let result = self
+            .client
+            .upload_version(&job.game_profile_id, &upload, &source)
+            .await?;
+        uploaded_version(result)
+    }
+
+    async fn list_versions(&self, game: &GameRef) -> Result<Vec<StoredVersion>, StorageError> {
+        let mut versions = Vec::new();
+        let mut page = 1;
+        loop {
+            let (batch, last) = self.fetch_listing_page(game, page).await?;
+            versions.extend(batch);
+            if last {
+                break;
+            }
+            page += 1;
+        }
+
+        let mut versions: Vec<StoredVersion> =
+            versions.into_iter().map(StoredVersion::from).collect();
+        versions.sort_by_key(|v| v.version_number);
+        Ok(versions)
+    }
+
+    async fn list_versions_page(
+        &self,
+        game: &GameRef,
+        page: u32,
+        page_size: u32,
+    ) -> Result<VersionPage, StorageError> {
+        match self.fetch_versions(game, page, page_size).await? {
+            VersionsResponse::All(all) => {
+                let mut versions: Vec<StoredVersion> =
+                    all.into_iter().map(StoredVersion::from).collect();
+                versions.sort_by_key(|v| v.version_number);
+                Ok(VersionPage::from_all(versions, page, page_size))
+            }
+            VersionsResponse::Page {
+                versions,
+                total,
+                has_more,
+            } => {
+                let has_more = has_more.unwrap_or(match total {
+                    Some(total) => (page as u64) * (page_size as u64) < total,
+                    None => versions.len() == page_size as usize,
+                });
+                Ok(VersionPage {
+                    versions: versions.into_iter().map(StoredVersion::from).collect(),
+                    page,
+                    page_size,
+                    total,
+                    has_more,
+                })
+            }
+        }
+    }
+
+    async fn get_version(
+        &self,
+        game: &GameRef,
+        version_id: &str,
+        dest: &Path,
+    ) -> Result<StoredVersion, StorageError> {
+        // Pages are newest first, so recent versions are found early.
+        let mut page = 1;
+        let version = loop {
+            let (batch, last) = self.fetch_listing_page(game, page).await?;
+            if let Some(version) = batch.into_iter().find(|v| v.id == version_id) {
+                break StoredVersion::from(version);
+            }
+            if last {
+                return Err(StorageError::Rejected(format!(
+                    "Version {} not found",
+                    version_id
+                )));
+            }
+            page += 1;
+        };
+
+        let response = self.client.download_version(version_id).await?;
+
+        save_response(response, dest).await?;
+
+        Ok(version)
+    }
+
+    async fn delete_version(&self, _game: &GameRef, version_id: &str) -> Result<(), StorageError> {
+        Ok(self.client.delete_version(version_id).await?)
+    }
+}
+
+/// Writes one entry of a zip to `dest`, on a blocking thread.
+async fn extract_entry(archive: &Path, name: &str, dest: &Path) -> std::io::Result<()> {
+    let archive = archive.to_path_buf();
+    let name = name.to_string();
+    let dest = dest.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut zip = ZipArchive::new(File::open(archive)?)?;
+        let mut entry = zip.by_name(&name)?;
+        let mut output = File::create(dest)?;
+        std::io::copy(&mut entry, &mut output)?;
+        Ok(())
+    })
+    .await?
+}
@@ -0,0 +1,289 @@
+use super::{
+    check_version_id, game_component, is_transient_status, new_version_id, next_version_number,
+    save_response, uri_encode, GameRef, StorageBackend, StorageError, StoredVersion,
+};
+use crate::client;
+use crate::config::StorageTarget;
+use crate::progress::ProgressReporter;
+use crate::queue::UploadJob;
+use crate::throttle::{throttled_file_stream, BandwidthLimiter};
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use regex::Regex;
+use reqwest::{Body, Method, RequestBuilder, Response, StatusCode, Url};
+use std::path::Path;
+use std::sync::Arc;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/></d:prop></d:propfind>"#;
+
+/// Keeps versions on a WebDAV server such as Nextcloud, using the same layout
+/// as the local backend: `<folder>/<game>/<version id>.zip` plus a `.json`
+/// sidecar. The password is kept in the keyring.
+pub struct WebDavBackend {
+    name: String,
+    base_url: String,
+    folder: Vec<String>,
+    username: String,
+    password: String,
+    bandwidth_limiter: Option<Arc<BandwidthLimiter>>,
+}
+
+impl WebDavBackend {
+    pub fn new(
+        target: &StorageTarget,
+        password: &str,
+        bandwidth_limiter: Option<Arc<BandwidthLimiter>>,
+    ) -> Result<Self, String> {
+        let StorageTarget::WebDav {
+            name,
+            url,
+            username,
+            folder,
+        } = target
+        else {
+            return Err("Not a WebDAV target".to_string());
+        };
+
+        let parsed = Url::parse(url).map_err(|e| format!("Invalid URL: {}", e))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err("The WebDAV URL must be an http or https URL".to_string());
+        }
+
+        Ok(Self {
+            name: name.clone(),
+            base_url: url.trim_end_matches('/').to_string(),
+            folder: folder
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .map(str::to_string)
+                .collect(),
+            username: username.clone(),
+            password: password.to_string(),
+            bandwidth_limiter,
+        })
+    }
+
+    /// Path segments of a game's directory below the base URL.
+    fn game_segments(&self, game_name: &str) -> Result<Vec<String>, StorageError> {
+        let mut segments = self.folder.clone();
+        segments.push(game_component(game_name)?);
+        Ok(segments)
+    }
+
+    fn url(&self, segments: &[String]) -> String {
+        let mut url = self.base_url.clone();
+        for segment in segments {
+            url.push('/');
+            url.push_str(&uri_encode(segment, true));
+        }
+        url
+    }
+
+    fn version_url(
+        &self,
+        game_name: &str,
+        version_id: &str,
+        extension: &str,
+    ) -> Result<String, StorageError> {
+        check_version_id(version_id)?;
+        let mut segments = self.game_segments(game_name)?;
+        segments.push(format!("{}.{}", version_id, extension));
+        Ok(self.url(&segments))
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        client::shared_http_client()
+            .request(method, url)
+            .basic_auth(&self.username, Some(&self.password))
+    }
+
+    /// Creates the game's directory and any missing parents.
+    async fn ensure_game_dir(&self, game_name: &str) -> Result<(), StorageError> {
+        let mkcol = Method::from_bytes(b"MKCOL").expect("valid method");
+        let segments = self.game_segments(game_name)?;
+
+        for depth in 1..=segments.len() {
+            let url = format!("{}/", self.url(&segments[..depth]));
+            let response = self.request(mkcol.clone(), &url).send().await?;
+            // 405 means the collection already exists.
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                check_status(response).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// IDs of the versions in a game's directory, from their `.json` sidecars.
+    async fn list_version_ids(&self, game_name: &str) -> Result<Vec<String>, StorageError> {
+        let propfind = Method::from_bytes(b"PROPFIND").expect("valid method");
+        let url = format!("{}/", self.url(&self.game_segments(game_name)?));
+        let response = self
+            .request(propfind, &url)
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let body = check_status(response).await?.text().await?;
+
+        // Servers differ in the namespace prefix they use, so match any.
+        let href = Regex::new(r"(?s)<(?:[\w-]+:)?href>(.*?)</(?:[\w-]+:)?href>")
+            .expect("valid href pattern");
+        Ok(href
+            .captures_iter(&body)
+            .filter_map(|c| {
+                let name = c[1]
+                    .trim()
+                    .trim_end_matches('/')
+                    .rsplit('/')
+                    .next()?
+                    .to_string();
+                let id = name.strip_suffix(".json")?;
+                check_version_id(id).ok()?;
+                Some(id.to_string())
+            })
+            .collect())
+    }
+
+    async fn read_version(&self, url: &str) -> Result<StoredVersion, StorageError> {
+        let response = check_status(self.request(Method::GET, url).send().await?).await?;
+        let data = response.bytes().await?;
+        serde_json::from_slice(&data).map_err(|e| StorageError::Rejected(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavBackend {
+    fn id(&self) -> String {
+        format!("webdav:{}", self.name)
+    }
+
+    async fn put_version(
+        &self,
+        job: &UploadJob,
+        progress: &ProgressReporter,
+    ) -> Result<StoredVersion, StorageError> {
+        let archive_path = Path::new(&job.archive_path);
+        let size = std::fs::metadata(archive_path)
+            .map_err(|e| StorageError::Rejected(format!("Staged archive is missing: {}", e)))?
+            .len();
+
+        self.ensure_game_dir(&job.game_name).await?;
+        let version_number = next_version_number(
+            &self
+                .list_versions(&GameRef {
+                    name: job.game_name.clone(),
+                    profile_id: None,
+                })
+                .await?,
+        );
+        let id = new_version_id();
+
+        let reporter = progress.clone();
+        let stream = throttled_file_stream(archive_path, self.bandwidth_limiter.clone())
+            .await?
+            .inspect_ok(move |chunk| reporter.bytes_sent(chunk.len() as u64));
+        progress.upload_started(size);
+
+        let response = self
+            .request(Method::PUT, &self.version_url(&job.game_name, &id, "zip")?)
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Length", size)
+            .body(Body::wrap_stream(stream))
+            .send()
+            .await?;
+        check_status(response).await?;
+
+        // The sidecar is written last, so a version only shows up in listings
+        // once its archive is complete.
+        let version = StoredVersion::for_job(job, id.clone(), version_number);
+        let metadata = serde_json::to_vec_pretty(&version)
+            .map_err(|e| StorageError::Rejected(e.to_string()))?;
+        let response = self
+            .request(Method::PUT, &self.version_url(&job.game_name, &id, "json")?)
+            .header("Content-Type", "application/json")
+            .body(metadata)
+            .send()
+            .await?;
+        check_status(response).await?;
+
+        Ok(version)
+    }
+
+    async fn list_versions(&self, game: &GameRef) -> Result<Vec<StoredVersion>, StorageError> {
+        let mut versions = Vec::new();
+        for id in self.list_version_ids(&game.name).await? {
+            match self
+                .read_version(&self.version_url(&game.name, &id, "json")?)
+                .await
+            {
+                Ok(version) => versions.push(version),
+                Err(StorageError::Rejected(e)) => {
+                    log::warn!("Skipping unreadable version metadata {}: {}", id, e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        versions.sort_by_key(|v| v.version_number);
+        Ok(versions)
+    }
+
+    async fn get_version(
+        &self,
+        game: &GameRef,
+        version_id: &str,
+        dest: &Path,
+    ) -> Result<StoredVersion, StorageError> {
+        let version = self
+            .read_version(&self.version_url(&game.name, version_id, "json")?)
+            .await?;
+
+        let response = self
+            .request(
+                Method::GET,
+                &self.version_url(&game.name, version_id, "zip")?,
+            )
+            .send()
+            .await?;
+        save_response(check_status(response).await?, dest).await?;
+
+        Ok(version)
+    }
+
+    async fn delete_version(&self, game: &GameRef, version_id: &str) -> Result<(), StorageError> {
+        // Sidecar first, so a half-deleted version is no longer listed.
+        for extension in ["json", "zip"] {
+            let response = self
+                .request(
+                    Method::DELETE,
+                    &self.version_url(&game.name, version_id, extension)?,
+                )
+                .send()
+                .await?;
+            if response.status() != StatusCode::NOT_FOUND || extension == "json" {
+                check_status(response).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn check_status(response: Response) -> Result<Response, StorageError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = format!("WebDAV server returned {}", status);
+    // Unlike the SaveKnight API, a 401 here means the stored password is
+    // wrong, which retrying won't fix.
+    if is_transient_status(status) && status != StatusCode::UNAUTHORIZED {
+        Err(StorageError::Transient(message))
+    } else {
+        Err(StorageError::Rejected(message))
+    }
+}
@@ -0,0 +1,153 @@
+use crate::config::{Config, TimeWindow};
+use chrono::NaiveTime;
+use futures_util::stream::Stream;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Checks the bandwidth settings of `config` without applying them.
+pub fn validate(config: &Config) -> Result<(), String> {
+    if let Some(window) = &config.bandwidth_limit_window {
+        parse_window(window)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LimitSettings {
+    bytes_per_sec: Option<u64>,
+    window: Option<(NaiveTime, NaiveTime)>,
+}
+
+struct Bucket {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * rate).min(rate);
+        self.last_refill = now;
+    }
+}
+
+/// Token bucket shared by every in-flight upload, so the configured limit
+/// caps the total rate rather than the rate of each upload.
+pub struct BandwidthLimiter {
+    settings: Mutex<LimitSettings>,
+    bucket: tokio::sync::Mutex<Bucket>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(config: &Config) -> Self {
+        let limiter = Self {
+            settings: Mutex::new(LimitSettings::default()),
+            bucket: tokio::sync::Mutex::new(Bucket {
+                available: 0.0,
+                last_refill: Instant::now(),
+            }),
+        };
+        if let Err(e) = limiter.update(config) {
+            log::warn!("Ignoring bandwidth limit: {}", e);
+        }
+        limiter
+    }
+
+    pub fn update(&self, config: &Config) -> Result<(), String> {
+        let window = match &config.bandwidth_limit_window {
+            Some(window) => Some(parse_window(window)?),
+            None => None,
+        };
+        let mut settings = self.settings.lock().map_err(|e| e.to_string())?;
+        *settings = LimitSettings {
+            bytes_per_sec: config.bandwidth_limit_bytes_per_sec.filter(|r| *r > 0),
+            window,
+        };
+        Ok(())
+    }
+
+    fn current_rate(&self) -> Option<u64> {
+        let settings = *self.settings.lock().unwrap_or_else(|e| e.into_inner());
+        let rate = settings.bytes_per_sec?;
+        match settings.window {
+            Some((start, end)) => {
+                let now = chrono::Local::now().time();
+                let inside = if start <= end {
+                    now >= start && now < end
+                } else {
+                    now >= start || now < end
+                };
+                inside.then_some(rate)
+            }
+            None => Some(rate),
+        }
+    }
+
+    /// Waits until `bytes` may be sent without exceeding the limit.
+    pub async fn acquire(&self, bytes: usize) {
+        let rate = match self.current_rate() {
+            Some(rate) => rate as f64,
+            None => return,
+        };
+
+        let mut bucket = self.bucket.lock().await;
+        bucket.refill(rate);
+        let needed = bytes as f64;
+        if bucket.available < needed {
+            let wait = (needed - bucket.available) / rate;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+            bucket.refill(rate);
+        }
+        bucket.available -= needed;
+    }
+}
+
+fn parse_window(window: &TimeWindow) -> Result<(NaiveTime, NaiveTime), String> {
+    let parse = |value: &str| {
+        NaiveTime::parse_from_str(value, "%H:%M")
+            .map_err(|_| format!("Invalid time '{}', expected HH:MM", value))
+    };
+    Ok((parse(&window.start)?, parse(&window.end)?))
+}
+
+/// Streams a file in chunks, waiting on the limiter before each one.
+pub async fn throttled_file_stream(
+    path: &Path,
+    limiter: Option<Arc<BandwidthLimiter>>,
+) -> std::io::Result<impl Stream<Item = std::io::Result<Vec<u8>>>> {
+    throttled_range_stream(path, 0, u64::MAX, limiter).await
+}
+
+/// Like [`throttled_file_stream`], but only streams `length` bytes starting
+/// at `offset`.
+pub async fn throttled_range_stream(
+    path: &Path,
+    offset: u64,
+    length: u64,
+    limiter: Option<Arc<BandwidthLimiter>>,
+) -> std::io::Result<impl Stream<Item = std::io::Result<Vec<u8>>>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    Ok(futures_util::stream::try_unfold(
+        (file.take(length), limiter),
+        |(mut file, limiter)| async move {
+            let mut buffer = vec![0; CHUNK_SIZE];
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            buffer.truncate(read);
+            if let Some(limiter) = &limiter {
+                limiter.acquire(read).await;
+            }
+            Ok(Some((buffer, (file, limiter))))
+        },
+    ))
+}
@@ -1,10 +1,18 @@
+use crate::compression;
+use crate::config::CompressionSettings;
+use crate::crypto::{self, EncryptionKey};
+use crate::mapping::{ArchiveRoot, BackupMapping, EncryptionInfo, MappedFile, MAPPING_FILE_NAME};
+use crate::paths;
+use crate::progress::ProgressReporter;
 use crate::scanner::DetectedGame;
-use reqwest::multipart::{Form, Part};
+use crate::snapshot::StagingArea;
+use chrono::{DateTime, Datelike, Local, Timelike, Utc};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use zip::write::FileOptions;
 use zip::ZipWriter;
 
@@ -13,179 +21,281 @@ pub struct UploadResult {
     pub game_name: String,
     pub success: bool,
     pub message: String,
+    /// The error's code when the upload failed or was queued.
+    pub code: Option<String>,
     pub upload_id: Option<String>,
     pub version_number: Option<i32>,
+    pub job_id: Option<String>,
+    pub target: Option<String>,
+    pub queued: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct UploadResponse {
-    success: bool,
-    save_version: Option<SaveVersionResponse>,
-    upload_id: Option<String>,
+/// A file (or, without a source, a directory) to write into the archive.
+struct ArchiveEntry {
+    name: String,
+    source: Option<PathBuf>,
+    pattern: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct SaveVersionResponse {
-    id: String,
-    version_number: i32,
+pub struct StagedArchive {
+    pub path: PathBuf,
+    pub checksum: String,
+    pub size_bytes: u64,
+    pub mapping: BackupMapping,
 }
 
+/// Builds the archives that get queued for upload.
+#[derive(Default)]
 pub struct Uploader {
-    api_url: String,
-    device_token: String,
+    encryption_key: Option<EncryptionKey>,
 }
 
 impl Uploader {
-    pub fn new(api_url: &str, device_token: &str) -> Self {
-        Self {
-            api_url: api_url.to_string(),
-            device_token: device_token.to_string(),
-        }
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
     }
 
-    pub async fn upload_game(
+    /// Builds the save archive for `game` at `output_path` so it can sit in
+    /// the upload queue until its storage targets are reachable. This does
+    /// blocking file I/O, so async callers run it with `spawn_blocking`.
+    pub fn stage_game(
         &self,
         game: &DetectedGame,
-        game_profile_id: &str,
-    ) -> Result<UploadResult, Box<dyn std::error::Error + Send + Sync>> {
-        let temp_dir = std::env::temp_dir();
-        let zip_path = temp_dir.join(format!("{}.zip", sanitize_filename(&game.name)));
-
-        self.create_save_zip(game, &zip_path)?;
-
-        let checksum = self.calculate_checksum(&zip_path)?;
-
-        let file_content = fs::read(&zip_path)?;
-        let file_size = file_content.len();
-
-        let form = Form::new()
-            .text("slotName", format!("{} Auto-Backup", game.name))
-            .text("localPath", game.paths.first().map(|p| p.resolved_path.clone()).unwrap_or_default())
-            .text("checksum", checksum)
-            .part(
-                "saveFile",
-                Part::bytes(file_content)
-                    .file_name(format!("{}.zip", sanitize_filename(&game.name)))
-                    .mime_str("application/zip")?,
-            );
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(format!("{}/api/devices/upload/{}", self.api_url, game_profile_id))
-            .header("Authorization", format!("Bearer {}", self.device_token))
-            .multipart(form)
-            .send()
-            .await?;
-
-        fs::remove_file(&zip_path).ok();
-
-        if response.status().is_success() {
-            let result: UploadResponse = response.json().await?;
-            Ok(UploadResult {
-                game_name: game.name.clone(),
-                success: true,
-                message: format!(
-                    "Uploaded {} bytes successfully",
-                    file_size
-                ),
-                upload_id: result.upload_id,
-                version_number: result.save_version.map(|v| v.version_number),
-            })
+        output_path: &Path,
+        compression: &CompressionSettings,
+        progress: &ProgressReporter,
+    ) -> Result<StagedArchive, Box<dyn std::error::Error + Send + Sync>> {
+        let partial_path = output_path.with_extension("zip.partial");
+        let encryption = self.encryption_key.as_ref().map(|key| key.info());
+        let mapping = match self.create_save_zip(game, &partial_path, compression, encryption, progress) {
+            Ok(mapping) => mapping,
+            Err(e) => {
+                fs::remove_file(&partial_path).ok();
+                return Err(e);
+            }
+        };
+
+        if let Some(key) = &self.encryption_key {
+            let encrypted_path = output_path.with_extension("enc.partial");
+            let encrypted = crypto::encrypt_file(&partial_path, &encrypted_path, key);
+            fs::remove_file(&partial_path).ok();
+            if let Err(e) = encrypted {
+                fs::remove_file(&encrypted_path).ok();
+                return Err(e.into());
+            }
+            fs::rename(&encrypted_path, output_path)?;
         } else {
-            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-            Ok(UploadResult {
-                game_name: game.name.clone(),
-                success: false,
-                message: error_text,
-                upload_id: None,
-                version_number: None,
-            })
+            fs::rename(&partial_path, output_path)?;
         }
+
+        // The checksum covers the bytes that leave the machine, i.e. the
+        // ciphertext when encryption is enabled.
+        let checksum = calculate_checksum(output_path)?;
+        let size_bytes = fs::metadata(output_path)?.len();
+
+        Ok(StagedArchive {
+            path: output_path.to_path_buf(),
+            checksum,
+            size_bytes,
+            mapping,
+        })
     }
 
     fn create_save_zip(
         &self,
         game: &DetectedGame,
         output_path: &Path,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        compression: &CompressionSettings,
+        encryption: Option<EncryptionInfo>,
+        progress: &ProgressReporter,
+    ) -> Result<BackupMapping, Box<dyn std::error::Error + Send + Sync>> {
+        let mut mapping = BackupMapping::new(&game.name, encryption);
+        let entries = self.collect_archive_entries(game, &mut mapping.roots)?;
+
+        // Copy everything first so the archive reflects a single point in
+        // time even if the game writes to its saves while we compress.
+        let staging = StagingArea::create(output_path.with_extension("files"))?;
+        let mut staged_files = Vec::with_capacity(entries.len());
+        for (index, entry) in entries.iter().enumerate() {
+            let staged = match &entry.source {
+                Some(source) => Some(staging.stage_file(index, source)?),
+                None => None,
+            };
+            staged_files.push(staged);
+        }
+
+        let files_total = staged_files.iter().filter(|s| s.is_some()).count() as u32;
+        let mut files_done = 0;
+        progress.archiving(files_done, files_total);
+
         let file = File::create(output_path)?;
         let mut zip = ZipWriter::new(file);
         let options = FileOptions::default()
             .compression_method(zip::CompressionMethod::Deflated)
             .unix_permissions(0o644);
 
+        for (entry, staged) in entries.iter().zip(&staged_files) {
+            match staged {
+                Some(staged) => {
+                    let mut file = File::open(&staged.staged)?;
+                    let mut buffer = Vec::new();
+                    file.read_to_end(&mut buffer)?;
+
+                    let mut file_options =
+                        compression::file_options(compression, &staged.source, &buffer);
+                    if let Some(modified) = staged.modified.and_then(zip_timestamp) {
+                        file_options = file_options.last_modified_time(modified);
+                    }
+                    zip.start_file(&entry.name, file_options)?;
+                    zip.write_all(&buffer)?;
+
+                    if !staged.consistent {
+                        log::warn!(
+                            "{} was still changing during backup; the archived copy may be incomplete",
+                            staged.source.display()
+                        );
+                    }
+                    let location = paths::locate(&staged.source, &entry.pattern);
+                    mapping.files.push(MappedFile {
+                        archive_name: entry.name.clone(),
+                        original_path: staged.source.to_string_lossy().to_string(),
+                        pattern: entry.pattern.clone(),
+                        placeholder: location.as_ref().map(|l| l.placeholder.clone()),
+                        relative_path: location.as_ref().map(|l| l.relative_path.clone()),
+                        save_root: location.as_ref().and_then(|l| l.save_root.clone()),
+                        placeholder_os: location
+                            .as_ref()
+                            .map(|l| l.os.clone())
+                            .filter(|os| *os != mapping.os),
+                        size_bytes: buffer.len() as u64,
+                        sha256: hex::encode(Sha256::digest(&buffer)),
+                        modified: staged.modified.map(DateTime::<Utc>::from),
+                        consistent: staged.consistent,
+                    });
+                    files_done += 1;
+                    progress.archiving(files_done, files_total);
+                }
+                None => zip.add_directory(&entry.name, options)?,
+            }
+        }
+
+        zip.start_file(MAPPING_FILE_NAME, options)?;
+        zip.write_all(&serde_json::to_vec_pretty(&mapping)?)?;
+
+        zip.finish()?;
+        Ok(mapping)
+    }
+
+    /// Every file and folder to archive. Each matched save path becomes a
+    /// root in `roots`, and its entries are named below it.
+    fn collect_archive_entries(
+        &self,
+        game: &DetectedGame,
+        roots: &mut Vec<ArchiveRoot>,
+    ) -> Result<Vec<ArchiveEntry>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut entries = Vec::new();
+
         for detected_path in &game.paths {
             if !detected_path.exists {
                 continue;
             }
 
             for entry in glob::glob(&detected_path.resolved_path).into_iter().flatten().flatten() {
+                let root = roots.len().to_string();
                 if entry.is_file() {
-                    let relative_name = entry
+                    let file_name = entry
                         .file_name()
                         .map(|n| n.to_string_lossy().to_string())
                         .unwrap_or_else(|| "file".to_string());
-                    
-                    zip.start_file(&relative_name, options)?;
-                    let mut file = File::open(&entry)?;
-                    let mut buffer = Vec::new();
-                    file.read_to_end(&mut buffer)?;
-                    zip.write_all(&buffer)?;
+                    roots.push(ArchiveRoot {
+                        name: root.clone(),
+                        path: entry.parent().unwrap_or(&entry).to_string_lossy().to_string(),
+                        pattern: detected_path.pattern.clone(),
+                    });
+                    entries.push(ArchiveEntry {
+                        name: format!("{}/{}", root, file_name),
+                        source: Some(entry),
+                        pattern: detected_path.pattern.clone(),
+                    });
                 } else if entry.is_dir() {
-                    self.add_dir_to_zip(&mut zip, &entry, &entry, options)?;
+                    roots.push(ArchiveRoot {
+                        name: root.clone(),
+                        path: entry.to_string_lossy().to_string(),
+                        pattern: detected_path.pattern.clone(),
+                    });
+                    self.collect_dir_entries(&mut entries, &root, &detected_path.pattern, &entry, &entry)?;
                 }
             }
         }
 
-        zip.finish()?;
-        Ok(())
+        Ok(entries)
     }
 
-    fn add_dir_to_zip<W: Write + std::io::Seek>(
+    fn collect_dir_entries(
         &self,
-        zip: &mut ZipWriter<W>,
+        entries: &mut Vec<ArchiveEntry>,
+        root: &str,
+        pattern: &str,
         base_path: &Path,
         current_path: &Path,
-        options: FileOptions,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         for entry in fs::read_dir(current_path)? {
             let entry = entry?;
             let path = entry.path();
             let relative = path.strip_prefix(base_path).unwrap_or(&path);
-            let name = relative.to_string_lossy().replace('\\', "/");
+            let name = format!("{}/{}", root, relative.to_string_lossy().replace('\\', "/"));
 
             if path.is_file() {
-                zip.start_file(&name, options)?;
-                let mut file = File::open(&path)?;
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)?;
-                zip.write_all(&buffer)?;
+                entries.push(ArchiveEntry {
+                    name,
+                    source: Some(path),
+                    pattern: pattern.to_string(),
+                });
             } else if path.is_dir() {
-                zip.add_directory(&name, options)?;
-                self.add_dir_to_zip(zip, base_path, &path, options)?;
+                entries.push(ArchiveEntry {
+                    name,
+                    source: None,
+                    pattern: pattern.to_string(),
+                });
+                self.collect_dir_entries(entries, root, pattern, base_path, &path)?;
             }
         }
         Ok(())
     }
+}
 
-    fn calculate_checksum(&self, path: &Path) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let mut file = File::open(path)?;
-        let mut hasher = Sha256::new();
-        let mut buffer = [0; 8192];
-        
-        loop {
-            let bytes_read = file.read(&mut buffer)?;
-            if bytes_read == 0 {
-                break;
-            }
-            hasher.update(&buffer[..bytes_read]);
+/// Hex SHA-256 of a file.
+pub(crate) fn calculate_checksum(path: &Path) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
         }
-        
-        Ok(hex::encode(hasher.finalize()))
+        hasher.update(&buffer[..bytes_read]);
     }
+    
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn zip_timestamp(time: SystemTime) -> Option<zip::DateTime> {
+    let local = DateTime::<Local>::from(time);
+    zip::DateTime::from_date_and_time(
+        local.year().try_into().ok()?,
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        local.second() as u8,
+    )
+    .ok()
 }
 
-fn sanitize_filename(name: &str) -> String {
+pub(crate) fn sanitize_filename(name: &str) -> String {
     name.chars()
         .map(|c| match c {
             '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
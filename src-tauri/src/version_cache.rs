@@ -0,0 +1,72 @@
+use crate::config::Config;
+use crate::storage::{GameRef, VersionPage};
+use crate::uploader::sanitize_filename;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// A page of version history as returned to the frontend, which may come
+/// from the on-disk cache when the target can't be reached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionHistoryPage {
+    #[serde(flatten)]
+    pub page: VersionPage,
+    pub fetched_at: DateTime<Utc>,
+    pub from_cache: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CachedHistory {
+    /// Keyed by `<page>:<page size>`.
+    pages: HashMap<String, VersionHistoryPage>,
+}
+
+fn cache_path(target: &str, game: &GameRef) -> PathBuf {
+    let key = game.profile_id.as_deref().unwrap_or(&game.name);
+    Config::data_dir()
+        .join("version-cache")
+        .join(sanitize_filename(target))
+        .join(format!("{}.json", sanitize_filename(key)))
+}
+
+fn page_key(page: u32, page_size: u32) -> String {
+    format!("{}:{}", page, page_size)
+}
+
+fn read(target: &str, game: &GameRef) -> CachedHistory {
+    fs::read(cache_path(target, game))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Remembers a freshly fetched page. Other page sizes are dropped, since
+/// their contents have shifted if new versions were uploaded.
+pub fn store(target: &str, game: &GameRef, page: &VersionHistoryPage) -> std::io::Result<()> {
+    let mut history = read(target, game);
+    history
+        .pages
+        .retain(|_, cached| cached.page.page_size == page.page.page_size);
+    history
+        .pages
+        .insert(page_key(page.page.page, page.page.page_size), page.clone());
+
+    let path = cache_path(target, game);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, serde_json::to_vec(&history)?)?;
+    fs::rename(temp_path, path)
+}
+
+/// The cached copy of a page, if it has been fetched before.
+pub fn load(target: &str, game: &GameRef, page: u32, page_size: u32) -> Option<VersionHistoryPage> {
+    let mut cached = read(target, game)
+        .pages
+        .remove(&page_key(page, page_size))?;
+    cached.from_cache = true;
+    Some(cached)
+}
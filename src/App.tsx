@@ -3,11 +3,31 @@ import { invoke } from '@tauri-apps/api/tauri';
 import { listen } from '@tauri-apps/api/event';
 import { Shield, Folder, Upload, RefreshCw, Check, X, Loader2, LogOut, ExternalLink } from 'lucide-react';
 
+/** Rejection value of every command; `code` is stable, `message` is for display. */
+interface AppError {
+  code: string;
+  message: string;
+  details: { game?: string; path?: string; status?: number };
+}
+
+function isAppError(e: unknown): e is AppError {
+  return typeof e === 'object' && e !== null && 'code' in e && 'message' in e;
+}
+
+/** Errors that stop a batch of uploads, since every later game would hit them too. */
+const FATAL_UPLOAD_ERRORS = ['not_authenticated', 'quota_exceeded'];
+
 interface DetectedGame {
   name: string;
   paths: DetectedSavePath[];
   total_size_bytes: number;
   last_modified: string | null;
+  store_ids: StoreIds;
+}
+
+interface StoreIds {
+  steam: number | null;
+  gog: number | null;
 }
 
 interface DetectedSavePath {
@@ -25,10 +45,39 @@ interface AuthStatus {
   plan_name: string | null;
 }
 
+interface MachineInfo {
+  machine_id: string;
+  os: string;
+  os_version: string | null;
+  arch: string;
+  hostname: string | null;
+}
+
+interface DeviceLoginPrompt {
+  user_code: string;
+  verification_uri: string;
+  verification_uri_complete: string | null;
+  expires_at: string;
+}
+
+interface UploadProgress {
+  job_id: string;
+  game_name: string;
+  stage: 'archiving' | 'uploading' | 'completed' | 'queued' | 'failed';
+  files_done: number;
+  files_total: number;
+  bytes_sent: number;
+  bytes_total: number;
+  bytes_per_sec: number;
+  eta_secs: number | null;
+  message: string | null;
+}
+
 interface GameProfile {
   id: string;
   name: string;
   platform: string;
+  store_ids: StoreIds;
 }
 
 function formatBytes(bytes: number): string {
@@ -44,16 +93,18 @@ export default function App() {
   const [detectedGames, setDetectedGames] = useState<DetectedGame[]>([]);
   const [selectedGames, setSelectedGames] = useState<Set<string>>(new Set());
   const [gameProfiles, setGameProfiles] = useState<GameProfile[]>([]);
+  const [profileMappings, setProfileMappings] = useState<Record<string, string>>({});
   const [isScanning, setIsScanning] = useState(false);
   const [isUploading, setIsUploading] = useState(false);
   const [activeTab, setActiveTab] = useState<'games' | 'settings'>('games');
   const [error, setError] = useState<string | null>(null);
   const [successMessage, setSuccessMessage] = useState<string | null>(null);
   
-  const [sessionCookie, setSessionCookie] = useState('');
+  const [deviceLogin, setDeviceLogin] = useState<DeviceLoginPrompt | null>(null);
   const [deviceName, setDeviceName] = useState('');
   const [isLoggingIn, setIsLoggingIn] = useState(false);
   const [showLoginForm, setShowLoginForm] = useState(false);
+  const [uploadProgress, setUploadProgress] = useState<Record<string, UploadProgress>>({});
 
   useEffect(() => {
     checkAuth();
@@ -62,14 +113,50 @@ export default function App() {
       handleScan();
     });
 
-    const hostname = window.navigator.userAgent.includes('Windows') ? 'Windows PC' : 'Desktop';
-    setDeviceName(hostname);
+    const unlistenProgress = listen<UploadProgress>('upload-progress', (event) => {
+      const progress = event.payload;
+      setUploadProgress((prev) => {
+        const next = { ...prev };
+        if (progress.stage === 'archiving' || progress.stage === 'uploading') {
+          next[progress.game_name] = progress;
+        } else {
+          delete next[progress.game_name];
+        }
+        return next;
+      });
+    });
+
+    invoke<MachineInfo>('get_machine_info')
+      .then((info) => setDeviceName(info.hostname ?? 'Desktop'))
+      .catch(() => setDeviceName('Desktop'));
 
     return () => {
       unlisten.then((fn) => fn());
+      unlistenProgress.then((fn) => fn());
     };
   }, []);
 
+  function showError(e: unknown) {
+    if (!isAppError(e)) {
+      showError(e);
+      return;
+    }
+    switch (e.code) {
+      case 'not_authenticated':
+        setAuthStatus({ is_authenticated: false, device_id: null, user_email: null, plan_name: null });
+        setError('You have been signed out of SaveKnight. Please connect your account again.');
+        break;
+      case 'quota_exceeded':
+        setError(`Your SaveKnight storage is full: ${e.message}`);
+        break;
+      case 'permission_denied':
+        setError(`${e.message}. Check that the folder isn't read-only and the game is closed.`);
+        break;
+      default:
+        setError(e.message);
+    }
+  }
+
   async function checkAuth() {
     try {
       const status = await invoke<AuthStatus>('get_auth_status');
@@ -78,7 +165,7 @@ export default function App() {
         loadGameProfiles();
       }
     } catch (e) {
-      setError(String(e));
+      showError(e);
     }
   }
 
@@ -86,16 +173,60 @@ export default function App() {
     try {
       const profiles = await invoke<GameProfile[]>('get_game_profiles');
       setGameProfiles(profiles);
+      setProfileMappings(await invoke<Record<string, string>>('get_game_profile_mappings'));
     } catch (e) {
       console.error('Failed to load game profiles:', e);
     }
   }
 
+  async function setGameProfile(gameName: string, gameProfileId: string | null) {
+    try {
+      await invoke('set_game_profile_mapping', { gameName, gameProfileId });
+      setProfileMappings((prev) => {
+        const next = { ...prev };
+        if (gameProfileId) {
+          next[gameName] = gameProfileId;
+        } else {
+          delete next[gameName];
+        }
+        return next;
+      });
+    } catch (e) {
+      showError(e);
+    }
+  }
+
+  function onLoggedIn(status: AuthStatus) {
+    setAuthStatus(status);
+    setShowLoginForm(false);
+    if (status.is_authenticated) {
+      loadGameProfiles();
+      setSuccessMessage('Successfully connected to SaveKnight!');
+      setTimeout(() => setSuccessMessage(null), 3000);
+    }
+  }
+
   async function handleLogin() {
-    if (!sessionCookie.trim()) {
-      setError('Please enter your session cookie');
+    if (!deviceName.trim()) {
+      setError('Please enter a device name');
       return;
     }
+
+    setIsLoggingIn(true);
+    setError(null);
+    try {
+      const status = await invoke<AuthStatus>('login_with_browser', {
+        deviceName: deviceName.trim(),
+      });
+      onLoggedIn(status);
+    } catch (e) {
+      showError(e);
+    } finally {
+      setIsLoggingIn(false);
+    }
+  }
+
+  async function handleDeviceLogin() {
     if (!deviceName.trim()) {
       setError('Please enter a device name');
       return;
@@ -104,25 +235,28 @@ export default function App() {
     setIsLoggingIn(true);
     setError(null);
     try {
-      const status = await invoke<AuthStatus>('login', {
-        sessionCookie: sessionCookie.trim(),
+      const prompt = await invoke<DeviceLoginPrompt>('start_device_login');
+      setDeviceLogin(prompt);
+      const status = await invoke<AuthStatus>('finish_device_login', {
         deviceName: deviceName.trim(),
       });
-      setAuthStatus(status);
-      setSessionCookie('');
-      setShowLoginForm(false);
-      if (status.is_authenticated) {
-        loadGameProfiles();
-        setSuccessMessage('Successfully connected to SaveKnight!');
-        setTimeout(() => setSuccessMessage(null), 3000);
-      }
+      onLoggedIn(status);
     } catch (e) {
-      setError(String(e));
+      showError(e);
     } finally {
+      setDeviceLogin(null);
       setIsLoggingIn(false);
     }
   }
 
+  async function cancelLogin() {
+    if (deviceLogin) {
+      await invoke('cancel_device_login');
+    }
+    setShowLoginForm(false);
+    setError(null);
+  }
+
   async function handleLogout() {
     try {
       await invoke('logout');
@@ -131,7 +265,7 @@ export default function App() {
       setSelectedGames(new Set());
       setGameProfiles([]);
     } catch (e) {
-      setError(String(e));
+      showError(e);
     }
   }
 
@@ -146,7 +280,7 @@ export default function App() {
         setTimeout(() => setSuccessMessage(null), 3000);
       }
     } catch (e) {
-      setError(String(e));
+      showError(e);
     } finally {
       setIsScanning(false);
     }
@@ -162,53 +296,40 @@ export default function App() {
     setError(null);
     let successCount = 0;
     let failCount = 0;
+    let fatalError: unknown = null;
 
     try {
       const gamesToUpload = detectedGames.filter((g) => selectedGames.has(g.name));
       
+      // Each game goes to its own profile, found or created by the backend.
       for (const game of gamesToUpload) {
-        let profile = gameProfiles.find((p) => p.name.toLowerCase() === game.name.toLowerCase());
-        
-        if (!profile) {
-          try {
-            const newProfile = await invoke<GameProfile>('create_game_profile', {
-              name: game.name,
-              platform: 'PC',
-            });
-            profile = newProfile;
-            setGameProfiles((prev) => [...prev, newProfile]);
-          } catch (e) {
-            console.error(`Failed to create profile for ${game.name}:`, e);
-            failCount++;
-            continue;
-          }
-        }
-        
-        if (profile) {
-          try {
-            await invoke('upload_saves', {
-              games: [game],
-              gameProfileId: profile.id,
-            });
-            successCount++;
-          } catch (e) {
-            console.error(`Failed to upload ${game.name}:`, e);
-            failCount++;
+        try {
+          await invoke('upload_saves', { games: [game] });
+          successCount++;
+        } catch (e) {
+          console.error(`Failed to upload ${game.name}:`, e);
+          if (isAppError(e) && FATAL_UPLOAD_ERRORS.includes(e.code)) {
+            fatalError = e;
+            break;
           }
+          failCount++;
         }
       }
       
       setSelectedGames(new Set());
+      loadGameProfiles();
       
       if (successCount > 0) {
         setSuccessMessage(`Successfully backed up ${successCount} game(s)!`);
         setTimeout(() => setSuccessMessage(null), 5000);
       }
-      if (failCount > 0) {
+      if (fatalError) {
+        showError(fatalError);
+      } else if (failCount > 0) {
         setError(`Failed to backup ${failCount} game(s). Check logs for details.`);
       }
     } catch (e) {
-      setError(String(e));
+      showError(e);
     } finally {
       setIsUploading(false);
     }
@@ -259,33 +380,35 @@ export default function App() {
               Connect Account
             </button>
             <p className="text-sm text-muted-foreground">
-              You'll need to sign in on the SaveKnight website first.
+              You'll sign in to SaveKnight in your browser.
             </p>
           </div>
         ) : (
           <div className="space-y-4 w-full max-w-md">
-            <div className="p-4 border rounded-lg bg-muted/30">
-              <h3 className="font-medium mb-2 flex items-center gap-2">
-                <span>Step 1: Sign in to SaveKnight</span>
-              </h3>
-              <a
-                href="https://saveknight.com"
-                target="_blank"
-                rel="noopener noreferrer"
-                className="inline-flex items-center gap-2 text-primary hover:underline text-sm"
-              >
-                Open SaveKnight website
-                <ExternalLink className="w-3 h-3" />
-              </a>
-            </div>
-
-            <div className="p-4 border rounded-lg bg-muted/30">
-              <h3 className="font-medium mb-2">Step 2: Copy your session cookie</h3>
-              <p className="text-sm text-muted-foreground mb-2">
-                After signing in, open browser DevTools (F12), go to Application {'>'} Cookies, 
-                and copy the value of "connect.sid".
-              </p>
-            </div>
+            {deviceLogin ? (
+              <div className="p-4 border rounded-lg bg-muted/30">
+                <h3 className="font-medium mb-2">Enter this code on another device</h3>
+                <p className="text-2xl font-mono tracking-widest text-center my-3">
+                  {deviceLogin.user_code}
+                </p>
+                <a
+                  href={deviceLogin.verification_uri_complete ?? deviceLogin.verification_uri}
+                  target="_blank"
+                  rel="noopener noreferrer"
+                  className="inline-flex items-center gap-2 text-primary hover:underline text-sm"
+                >
+                  {deviceLogin.verification_uri}
+                  <ExternalLink className="w-3 h-3" />
+                </a>
+              </div>
+            ) : (
+              <div className="p-4 border rounded-lg bg-muted/30">
+                <p className="text-sm text-muted-foreground">
+                  Your browser will open so you can sign in to SaveKnight. This device is
+                  connected once you approve it.
+                </p>
+              </div>
+            )}
 
             <div className="space-y-3">
               <div>
@@ -298,22 +421,9 @@ export default function App() {
                   className="w-full px-3 py-2 border rounded-md bg-background"
                 />
               </div>
-              <div>
-                <label className="block text-sm font-medium mb-1">Session Cookie</label>
-                <input
-                  type="password"
-                  value={sessionCookie}
-                  onChange={(e) => setSessionCookie(e.target.value)}
-                  placeholder="Paste connect.sid value here"
-                  className="w-full px-3 py-2 border rounded-md bg-background font-mono text-sm"
-                />
-              </div>
               <div className="flex gap-2">
                 <button
-                  onClick={() => {
-                    setShowLoginForm(false);
-                    setError(null);
-                  }}
+                  onClick={cancelLogin}
                   className="flex-1 px-4 py-2 border rounded-md hover:bg-muted"
                 >
                   Cancel
@@ -326,13 +436,21 @@ export default function App() {
                   {isLoggingIn ? (
                     <>
                       <Loader2 className="w-4 h-4 animate-spin" />
-                      Connecting...
+                      Waiting for sign-in...
                     </>
                   ) : (
-                    'Connect'
+                    'Sign in with browser'
                   )}
                 </button>
               </div>
+              {!isLoggingIn && (
+                <button
+                  onClick={handleDeviceLogin}
+                  className="w-full text-sm text-muted-foreground hover:underline"
+                >
+                  Can't open a browser here? Sign in with a code instead
+                </button>
+              )}
             </div>
           </div>
         )}
@@ -477,6 +595,30 @@ export default function App() {
                             <p className="text-sm text-muted-foreground">
                               {game.paths.filter((p) => p.exists).length} save location(s) found
                             </p>
+                            {gameProfiles.length > 0 && (
+                              <select
+                                value={profileMappings[game.name] ?? ''}
+                                onClick={(e) => e.stopPropagation()}
+                                onChange={(e) => setGameProfile(game.name, e.target.value || null)}
+                                className="mt-1 text-sm border rounded px-1 py-0.5 bg-background"
+                              >
+                                <option value="">Profile: automatic</option>
+                                {gameProfiles.map((profile) => (
+                                  <option key={profile.id} value={profile.id}>
+                                    Profile: {profile.name}
+                                  </option>
+                                ))}
+                              </select>
+                            )}
+                            {uploadProgress[game.name] && (
+                              <p className="text-sm text-primary">
+                                {uploadProgress[game.name].stage === 'archiving'
+                                  ? `Archiving ${uploadProgress[game.name].files_done}/${uploadProgress[game.name].files_total} files`
+                                  : `Uploading ${formatBytes(uploadProgress[game.name].bytes_sent)} of ${formatBytes(uploadProgress[game.name].bytes_total)} (${formatBytes(uploadProgress[game.name].bytes_per_sec)}/s${
+                                      uploadProgress[game.name].eta_secs !== null ? `, ${uploadProgress[game.name].eta_secs}s left` : ''
+                                    })`}
+                              </p>
+                            )}
                           </div>
                         </div>
                         <div className="text-right text-sm">